@@ -0,0 +1,152 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use psrs_client::{Client, Event};
+use psrs_protocol::canvas::{canvas_png, image_to_canvas};
+use psrs_protocol::discovery::DISCOVERY_PORT;
+use psrs_protocol::*;
+
+const USAGE: &str = "Usage: psrs_cli [--server <address:port>] [--name <name>] <command>
+
+Commands:
+  send <image>                           send a PNG or JPEG as a drawing
+  tail <dir> [--history] [--count <n>]   save incoming drawings to dir as PNGs
+  discover                               list servers on the local network
+
+The server defaults to localhost:6969 and the name to \"cli\".";
+
+const DEFAULT_SERVER: &str = "localhost:6969";
+const DEFAULT_NAME: &str = "cli";
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn connect(server: &str, name: &str) -> Result<Client, String> {
+    Client::connect(server, name).map_err(|e| match e {
+        psrs_client::Error::Io(e) => format!("can't connect to {server}: {e}"),
+        e => e.to_string()
+    })
+}
+
+fn describe_error(code: i32) -> String {
+    match ProtocolError::from_code(code) {
+        Some(error) => format!("{error:?}"),
+        None => format!("unknown error {code}")
+    }
+}
+
+fn send_image(server: &str, name: &str, path: &str) -> Result<(), String> {
+    let image = image::open(path).map_err(|e| format!("can't read {path}: {e}"))?;
+
+    let mut client = connect(server, name)?;
+    client.fetch_history().map_err(|e| e.to_string())?;
+    let sent = client.send_drawing(image_to_canvas(&image)).map_err(|e| e.to_string())?;
+
+    // The server echoes the drawing back to everyone, us included, once it's stored
+    client.set_read_timeout(Some(SEND_TIMEOUT)).map_err(|e| e.to_string())?;
+    loop {
+        match client.next_event().map_err(|e| e.to_string())? {
+            Event::Drawing(echo) if echo.name == sent.name && echo.timestamp == sent.timestamp => {
+                println!("{}", echo.id);
+                return Ok(());
+            },
+            Event::DrawingRefused(0) => return Err(String::from("server rejected the drawing")),
+            Event::DrawingRefused(code) | Event::ServerError(code) => return Err(format!("server rejected the drawing: {}", describe_error(code))),
+            Event::Kicked => return Err(String::from("kicked by the server")),
+            Event::ServerShutdown => return Err(String::from("server is shutting down")),
+            _ => {}
+        }
+    }
+}
+
+fn save_drawing(dir: &Path, texture_data: &TextureData) -> Result<(), String> {
+    let path = dir.join(format!("{}.png", texture_data.id));
+    std::fs::write(&path, canvas_png(&texture_data.data)).map_err(|e| format!("can't write {}: {e}", path.display()))?;
+    println!("{}\t{}\t{}\t{}", texture_data.id, display_name(&texture_data.name), texture_data.timestamp, path.display());
+    Ok(())
+}
+
+// Prints one tab separated line per event: drawings as id, name, timestamp and
+// the PNG written; "deleted <id>" and "announcement <text>" for the rest
+fn tail(server: &str, name: &str, dir: &Path, with_history: bool, count: Option<usize>) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("can't create {}: {e}", dir.display()))?;
+    let mut client = connect(server, name)?;
+    let history = client.fetch_history().map_err(|e| e.to_string())?;
+    if with_history {
+        for texture_data in &history {
+            save_drawing(dir, texture_data)?;
+        }
+    }
+
+    let mut saved = 0;
+    while count.is_none_or(|count| saved < count) {
+        match client.next_event().map_err(|e| e.to_string())? {
+            Event::Drawing(texture_data) => {
+                save_drawing(dir, &texture_data)?;
+                saved += 1;
+            },
+            Event::Deleted(id) => println!("deleted\t{id}"),
+            Event::Announcement(text) => println!("announcement\t{text}"),
+            Event::ServerError(code) => eprintln!("Server reported an error: {}", describe_error(code)),
+            Event::Kicked => return Err(String::from("kicked by the server")),
+            Event::ServerShutdown => {
+                eprintln!("Server is shutting down");
+                return Ok(());
+            },
+            Event::DeleteRefused(_) | Event::DrawingRefused(_) | Event::Activity(_) | Event::Board(_) | Event::Stroke(_) | Event::BoardCleared | Event::Reactions(_) => {}
+        }
+        std::io::stdout().flush().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// One tab separated line per server: address, name, clients online and messages in its history
+fn discover() -> Result<(), String> {
+    let servers = psrs_client::discover(DISCOVERY_PORT, DISCOVERY_TIMEOUT).map_err(|e| format!("discovery failed: {e}"))?;
+    if servers.is_empty() {
+        eprintln!("No servers found");
+    }
+    for server in servers {
+        println!("{}\t{}\t{}\t{}", server.addr, server.info.name, server.info.clients, server.info.messages);
+    }
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let mut server = String::from(DEFAULT_SERVER);
+    let mut name = String::from(DEFAULT_NAME);
+    let mut with_history = false;
+    let mut count = None;
+    let mut positional = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().cloned().ok_or_else(|| format!("{flag} needs a value"));
+        match arg.as_str() {
+            "--server" => server = value("--server")?,
+            "--name" => name = value("--name")?,
+            "--history" => with_history = true,
+            "--count" => count = Some(value("--count")?.parse::<usize>().map_err(|e| format!("bad --count: {e}"))?),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            },
+            flag if flag.starts_with("--") => return Err(format!("unknown option {flag}\n\n{USAGE}")),
+            _ => positional.push(arg.as_str())
+        }
+    }
+
+    match positional.as_slice() {
+        ["send", path] => send_image(&server, &name, path),
+        ["discover"] => discover(),
+        ["tail", dir] => tail(&server, &name, &PathBuf::from(dir), with_history, count),
+        _ => Err(String::from(USAGE))
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,128 @@
+// Serverless mode for when everyone is on one subnet: each peer multicasts its
+// drawings to a group and keeps its own history of what it hears. A datagram is
+// exactly what a server would send, a Drawing header followed by the drawing, or
+// an Activity message while someone is drawing.
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+use psrs_protocol::*;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{drawing_bytes, drawing_packet, Error, Event};
+
+pub const LAN_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 69, 69), 6967);
+
+// Nobody else is there to check what peers send, so anything larger than a
+// drawing is dropped along with anything that doesn't decode
+const MAX_DATAGRAM_SIZE: usize = INFO_SIZE + PACKET_SIZE;
+
+pub struct LanPeer {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    name: [u8; NAME_SIZE],
+    // There's no server handing out ids, so every peer numbers drawings as they arrive
+    next_id: i32
+}
+
+impl LanPeer {
+    // Joins group on the default interface. Any number of peers can join from
+    // the same machine; everyone, us included, hears every drawing sent to it.
+    pub fn join(group: SocketAddrV4, name: &str) -> Result<LanPeer, Error> {
+        let name = pad_name(name).map_err(Error::InvalidName)?;
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+        socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+        // Stay on this subnet and hear our own drawings, the way a server echoes them back
+        socket.set_multicast_ttl_v4(1)?;
+        socket.set_multicast_loop_v4(true)?;
+        Ok(LanPeer { socket: socket.into(), group, name, next_id: 0 })
+    }
+
+    // Another handle on the same membership, e.g. to send from one thread while
+    // another waits for events. Each handle numbers the drawings it receives itself.
+    pub fn try_clone(&self) -> Result<LanPeer, Error> {
+        Ok(LanPeer {
+            socket: self.socket.try_clone()?,
+            group: self.group,
+            name: self.name,
+            next_id: self.next_id
+        })
+    }
+
+    // Sends a CANVAS_SIZE canvas to every peer in the group and returns what was sent.
+    // Like with a server, our own copy comes back as Event::Drawing.
+    pub fn send_drawing(&self, canvas: Vec<u8>) -> Result<TextureData, Error> {
+        let (texture_data, packet) = drawing_packet(self.name, canvas, 0)?;
+        self.socket.send_to(&packet, self.group)?;
+        Ok(texture_data)
+    }
+
+    // Sends a drawing made earlier, e.g. one that was waiting for the network to come back.
+    // It goes out as it is, under its own name and timestamp.
+    pub fn resend(&self, texture_data: &TextureData) -> Result<(), Error> {
+        if texture_data.data.len() != CANVAS_SIZE {
+            return Err(Error::Protocol(format!("canvas is {} bytes, expected {CANVAS_SIZE}", texture_data.data.len())));
+        }
+        self.socket.send_to(&drawing_bytes(texture_data), self.group)?;
+        Ok(())
+    }
+
+    // Lets the other peers know we're drawing
+    pub fn activity(&self) -> Result<(), Error> {
+        self.socket.send_to(&activity(&self.name), self.group)?;
+        Ok(())
+    }
+
+    // Blocks until a peer sends a drawing or activity (or the read timeout runs out).
+    // Those are the only things peers exchange.
+    pub fn next_event(&mut self) -> Result<Event, Error> {
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE + 1];
+        loop {
+            let len = self.socket.recv(&mut buffer)?;
+            match decode_datagram(&buffer[..len]) {
+                Some(Datagram::Drawing(mut texture_data)) => {
+                    self.next_id += 1;
+                    texture_data.id = self.next_id;
+                    // Whatever it answers was numbered by someone else, so it can't be found here
+                    texture_data.reply_to = 0;
+                    return Ok(Event::Drawing(texture_data));
+                },
+                // Our own comes back to us too, which nobody wants to hear about
+                Some(Datagram::Activity(name)) if name != self.name => return Ok(Event::Activity(display_name(&name))),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.socket.set_read_timeout(timeout)?;
+        Ok(())
+    }
+
+    pub fn name(&self) -> String {
+        display_name(&self.name)
+    }
+}
+
+enum Datagram {
+    Drawing(TextureData),
+    Activity([u8; NAME_SIZE])
+}
+
+fn decode_datagram(bytes: &[u8]) -> Option<Datagram> {
+    if bytes.len() < INFO_SIZE || bytes.len() > MAX_DATAGRAM_SIZE {
+        return None;
+    }
+    let (header, payload) = bytes.split_at(INFO_SIZE);
+    let header = decode_header(header).ok()?;
+    if header.number as usize != payload.len() {
+        return None;
+    }
+    match header.msg {
+        InfoMsg::Drawing => decode_drawing(payload, now_millis()).ok().map(Datagram::Drawing),
+        InfoMsg::Activity => decode_activity(payload).ok().map(Datagram::Activity),
+        _ => None
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use psrs_protocol::canvas::Stroke;
+use psrs_protocol::discovery::{self, ServerInfo};
+use psrs_protocol::*;
+use uuid::Uuid;
+
+pub mod lan;
+
+// The largest history we'll accept: MAX_HISTORY drawings plus the Vec's length prefix
+const MAX_HISTORY_BYTES: usize = 8 + 56 * PACKET_SIZE;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    // The server sent something we couldn't make sense of
+    Protocol(String),
+    InvalidName(String)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "lost connection: {e}"),
+            Error::Protocol(e) => write!(f, "bad message from server: {e}"),
+            Error::InvalidName(e) => write!(f, "invalid name: {e}")
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+// Everything the server can tell us once we've joined
+#[derive(Debug, Clone)]
+pub enum Event {
+    Drawing(TextureData),
+    Deleted(i32),
+    DeleteRefused(i32),
+    // The oldest drawing we sent that hasn't come back was turned away. Error code
+    // as for ServerError, or 0 if a plugin did it, in which case an Announcement says why.
+    DrawingRefused(i32),
+    Announcement(String),
+    // Error code; ProtocolError::from_code turns known ones into something readable
+    ServerError(i32),
+    Kicked,
+    ServerShutdown,
+    // Someone else is drawing or typing something to send
+    Activity(String),
+    // The whole shared canvas, sent once we've joined it
+    Board(Vec<u8>),
+    // Drawn on the shared canvas by someone else
+    Stroke(Stroke),
+    BoardCleared,
+    // A message's reaction totals, after each change and for every message with any when joining
+    Reactions(ReactionCounts)
+}
+
+// The writing half of a connection. Clones share the socket, so one thread can
+// send while another waits for events.
+#[derive(Clone)]
+pub struct Sender {
+    stream: Arc<Mutex<TcpStream>>,
+    name: [u8; NAME_SIZE]
+}
+
+impl Sender {
+    fn send(&self, bytes: &[u8]) -> Result<(), Error> {
+        self.stream.lock().unwrap().write_all(bytes)?;
+        Ok(())
+    }
+
+    // Sends a CANVAS_SIZE canvas as a drawing and returns what was sent. The id is
+    // only known once the server echoes it back as Event::Drawing.
+    pub fn send_drawing(&self, canvas: Vec<u8>) -> Result<TextureData, Error> {
+        self.send_reply(canvas, 0)
+    }
+
+    // A drawing answering the one with id reply_to. Servers keep that on drawings
+    // from their own clients; relayed ones arrive without it.
+    pub fn send_reply(&self, canvas: Vec<u8>, reply_to: i32) -> Result<TextureData, Error> {
+        let (texture_data, packet) = drawing_packet(self.name, canvas, reply_to)?;
+        self.send(&packet)?;
+        Ok(texture_data)
+    }
+
+    // Sends a drawing made earlier, e.g. one queued while we were disconnected.
+    // It goes out as it is, so the echo can be matched up by name and timestamp.
+    pub fn resend(&self, texture_data: &TextureData) -> Result<(), Error> {
+        if texture_data.data.len() != CANVAS_SIZE {
+            return Err(Error::Protocol(format!("canvas is {} bytes, expected {CANVAS_SIZE}", texture_data.data.len())));
+        }
+        self.send(&drawing_bytes(texture_data))
+    }
+
+    // Only the drawing's author, going by the key we said hello with, may delete
+    // it; the answer arrives as Deleted or DeleteRefused
+    pub fn delete(&self, id: i32) -> Result<(), Error> {
+        self.send(&InfoData::new(InfoMsg::DeleteMessage, id).to_bytes())
+    }
+
+    // Stamps a reaction on a message, or takes it back if we already had.
+    // Everyone, us included, gets the new totals as Event::Reactions.
+    pub fn react(&self, message_id: i32, reaction: Reaction) -> Result<(), Error> {
+        self.send(&react(&React { message_id, reaction }))
+    }
+
+    // Lets everyone else know we're drawing. Servers pass on one a second per client at most.
+    pub fn activity(&self) -> Result<(), Error> {
+        self.send(&activity(&self.name))
+    }
+
+    // Answered with Event::Board, then Event::Stroke for everything drawn on it after
+    pub fn join_board(&self) -> Result<(), Error> {
+        self.send(&InfoData::new(InfoMsg::JoinBoard, 0).to_bytes())
+    }
+
+    pub fn leave_board(&self) -> Result<(), Error> {
+        self.send(&InfoData::new(InfoMsg::LeaveBoard, 0).to_bytes())
+    }
+
+    // Only goes to the others, so draw it on our own copy of the board too
+    pub fn stroke(&self, line: &Stroke) -> Result<(), Error> {
+        self.send(&stroke(line))
+    }
+
+    pub fn clear_board(&self) -> Result<(), Error> {
+        self.send(&InfoData::new(InfoMsg::ClearBoard, 0).to_bytes())
+    }
+
+    // Everyone on the board gets Event::BoardCleared as it's taken, then it comes back as Event::Drawing
+    pub fn post_board(&self) -> Result<(), Error> {
+        self.send(&post_board(&self.name))
+    }
+
+    // Closes the connection, which also ends a subscribe() thread
+    pub fn disconnect(&self) {
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
+
+    pub fn name(&self) -> String {
+        display_name(&self.name)
+    }
+}
+
+pub struct Client {
+    reader: TcpStream,
+    sender: Sender,
+    key: [u8; KEY_SIZE],
+    // Servers only take one Hello per connection
+    said_hello: bool,
+    // Events that arrived while we were waiting for something else
+    pending: VecDeque<Event>
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs, name: &str) -> Result<Client, Error> {
+        // Checked before connecting so a bad name doesn't cost a connection
+        pad_name(name).map_err(Error::InvalidName)?;
+        Client::from_stream(TcpStream::connect(addr)?, name)
+    }
+
+    // For a connection opened some other way, e.g. with a timeout
+    pub fn from_stream(reader: TcpStream, name: &str) -> Result<Client, Error> {
+        let name = pad_name(name).map_err(Error::InvalidName)?;
+        let writer = reader.try_clone()?;
+        Ok(Client {
+            reader,
+            sender: Sender { stream: Arc::new(Mutex::new(writer)), name },
+            key: new_key(),
+            said_hello: false,
+            pending: VecDeque::new()
+        })
+    }
+
+    // What we're known by when deleting. Each Client makes up its own, so keep one
+    // from new_key() and set it before the first fetch_history() to be able to
+    // delete what was sent over an earlier connection.
+    pub fn set_key(&mut self, key: [u8; KEY_SIZE]) {
+        self.key = key;
+    }
+
+    // Says hello, does the history handshake and returns the history. The server only
+    // sends us new drawings after the first one, so call this before waiting for events.
+    pub fn fetch_history(&mut self) -> Result<Vec<TextureData>, Error> {
+        if !self.said_hello {
+            self.sender.send(&hello(&Hello { name: self.sender.name, key: self.key }))?;
+            self.said_hello = true;
+        }
+        self.sender.send(&InfoData::new(InfoMsg::RequestHistoryLength, 0).to_bytes())?;
+        let length = loop {
+            let info = self.read_header()?;
+            if info.msg == InfoMsg::HistoryLength {
+                break info.number;
+            }
+            if let Some(event) = self.read_event(info)? {
+                self.pending.push_back(event);
+            }
+        };
+        self.sender.send(&InfoData::new(InfoMsg::RequestHistory, 0).to_bytes())?;
+        let bytes = self.read_payload(length, MAX_HISTORY_BYTES)?;
+        let history = decode(&bytes, MAX_HISTORY_BYTES).map_err(|e| Error::Protocol(e.to_string()))?;
+        self.sender.send(&InfoData::new(InfoMsg::ConfirmReceivedHistory, 0).to_bytes())?;
+        Ok(history)
+    }
+
+    // Blocks until the server tells us something (or the read timeout runs out)
+    pub fn next_event(&mut self) -> Result<Event, Error> {
+        if let Some(event) = self.pending.pop_front() {
+            return Ok(event);
+        }
+        loop {
+            let info = self.read_header()?;
+            if let Some(event) = self.read_event(info)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.reader.set_read_timeout(timeout)?;
+        Ok(())
+    }
+
+    pub fn sender(&self) -> Sender {
+        self.sender.clone()
+    }
+
+    pub fn send_drawing(&self, canvas: Vec<u8>) -> Result<TextureData, Error> {
+        self.sender.send_drawing(canvas)
+    }
+
+    pub fn send_reply(&self, canvas: Vec<u8>, reply_to: i32) -> Result<TextureData, Error> {
+        self.sender.send_reply(canvas, reply_to)
+    }
+
+    pub fn delete(&self, id: i32) -> Result<(), Error> {
+        self.sender.delete(id)
+    }
+
+    pub fn activity(&self) -> Result<(), Error> {
+        self.sender.activity()
+    }
+
+    pub fn react(&self, message_id: i32, reaction: Reaction) -> Result<(), Error> {
+        self.sender.react(message_id, reaction)
+    }
+
+    // Hands the reading half to its own thread. Events arrive on the returned
+    // channel; the last thing sent is the error that ended the connection.
+    pub fn subscribe(mut self) -> (Sender, Receiver<Result<Event, Error>>) {
+        let sender = self.sender();
+        let (events, receiver) = mpsc::channel();
+        thread::spawn(move || loop {
+            let event = self.next_event();
+            let failed = event.is_err();
+            if events.send(event).is_err() || failed {
+                break;
+            }
+        });
+        (sender, receiver)
+    }
+
+    fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        let mut buffer = vec![0; len];
+        self.reader.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn read_header(&mut self) -> Result<InfoData, Error> {
+        let header = self.read_exact(INFO_SIZE)?;
+        decode(&header, INFO_SIZE).map_err(|e| Error::Protocol(e.to_string()))
+    }
+
+    fn read_payload(&mut self, number: i32, limit: usize) -> Result<Vec<u8>, Error> {
+        if number < 0 || number as usize > limit {
+            return Err(Error::Protocol(format!("announced a {number} byte message, expected at most {limit}")));
+        }
+        self.read_exact(number as usize)
+    }
+
+    // Reads whatever follows the header; None for messages clients don't care about
+    fn read_event(&mut self, info: InfoData) -> Result<Option<Event>, Error> {
+        Ok(Some(match info.msg {
+            InfoMsg::Drawing => {
+                let bytes = self.read_payload(info.number, PACKET_SIZE)?;
+                Event::Drawing(decode(&bytes, PACKET_SIZE).map_err(|e| Error::Protocol(e.to_string()))?)
+            },
+            InfoMsg::Announcement => {
+                let bytes = self.read_payload(info.number, MAX_ANNOUNCEMENT_SIZE)?;
+                Event::Announcement(String::from_utf8_lossy(&bytes).into_owned())
+            },
+            InfoMsg::MessageDeleted => Event::Deleted(info.number),
+            InfoMsg::DeleteRefused => Event::DeleteRefused(info.number),
+            InfoMsg::DrawingRefused => Event::DrawingRefused(info.number),
+            InfoMsg::Error => Event::ServerError(info.number),
+            InfoMsg::Kicked => Event::Kicked,
+            InfoMsg::ServerShutdown => Event::ServerShutdown,
+            InfoMsg::Activity => {
+                let bytes = self.read_payload(info.number, NAME_SIZE)?;
+                let name = decode_activity(&bytes).map_err(|e| Error::Protocol(format!("{e:?}")))?;
+                Event::Activity(display_name(&name))
+            },
+            InfoMsg::Board => Event::Board(self.read_payload(info.number, CANVAS_SIZE)?),
+            InfoMsg::Stroke => {
+                let bytes = self.read_payload(info.number, STROKE_SIZE)?;
+                Event::Stroke(decode_stroke(&bytes).map_err(|e| Error::Protocol(format!("{e:?}")))?)
+            },
+            InfoMsg::ClearBoard => Event::BoardCleared,
+            InfoMsg::ReactionCounts => {
+                let bytes = self.read_payload(info.number, REACTION_COUNTS_SIZE)?;
+                Event::Reactions(decode_reaction_counts(&bytes).map_err(|e| Error::Protocol(format!("{e:?}")))?)
+            },
+            _ => return Ok(None)
+        }))
+    }
+}
+
+// A fresh secret to say hello with
+pub fn new_key() -> [u8; KEY_SIZE] {
+    *Uuid::new_v4().as_bytes()
+}
+
+// A drawing stamped with the current time, and the header and bytes to send it with
+fn drawing_packet(name: [u8; NAME_SIZE], canvas: Vec<u8>, reply_to: i32) -> Result<(TextureData, Vec<u8>), Error> {
+    if canvas.len() != CANVAS_SIZE {
+        return Err(Error::Protocol(format!("canvas is {} bytes, expected {CANVAS_SIZE}", canvas.len())));
+    }
+    let texture_data = TextureData {
+        name,
+        data: canvas,
+        request_history: false,
+        request_history_length: false,
+        history_length: 0,
+        confirm_history: false,
+        timestamp: now_millis(),
+        id: 0,
+        reply_to
+    };
+    let packet = drawing_bytes(&texture_data);
+    Ok((texture_data, packet))
+}
+
+fn drawing_bytes(texture_data: &TextureData) -> Vec<u8> {
+    let mut packet = InfoData::new(InfoMsg::Drawing, PACKET_SIZE as i32).to_bytes();
+    packet.extend(bincode::serialize(texture_data).unwrap());
+    packet
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredServer {
+    // Where to connect: the address the reply came from with the server's port
+    pub addr: SocketAddr,
+    pub info: ServerInfo
+}
+
+// Asks the local network (and this machine) which servers are answering
+// discovery queries on port, collecting replies until timeout runs out
+pub fn discover(port: u16, timeout: Duration) -> Result<Vec<DiscoveredServer>, Error> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    let query = discovery::query();
+    // Broadcasts don't reach servers on this machine everywhere, so ask loopback directly too.
+    // Either may fail (no network, no broadcast route) without the other being useless.
+    let sent = [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST].into_iter()
+        .filter(|ip| socket.send_to(&query, (*ip, port)).is_ok())
+        .count();
+    if sent == 0 {
+        return Err(Error::Io(std::io::ErrorKind::NetworkUnreachable.into()));
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut found: Vec<DiscoveredServer> = Vec::new();
+    let mut buffer = [0; 512];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (len, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e.into())
+        };
+        if let Some(info) = discovery::decode_reply(&buffer[..len]) {
+            let addr = SocketAddr::new(from.ip(), info.port);
+            if !found.iter().any(|server| server.info.id == info.id) {
+                found.push(DiscoveredServer { addr, info });
+            }
+        }
+    }
+    Ok(found)
+}
@@ -0,0 +1,260 @@
+// The server's own test harness, so both crates start servers the same way
+#[path = "../../psrs_server/tests/common/mod.rs"]
+mod common;
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use common::{TestServer, TIMEOUT};
+use psrs_client::{Client, Error, Event};
+use psrs_protocol::canvas::{draw_stroke, Pen, Stroke, BACKGROUND};
+use psrs_protocol::*;
+
+fn join(addr: SocketAddr, name: &str) -> Client {
+    let mut client = Client::connect(addr, name).unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+    client.fetch_history().unwrap();
+    client
+}
+
+fn canvas(value: u8) -> Vec<u8> {
+    vec![value; CANVAS_SIZE]
+}
+
+fn next_drawing(client: &mut Client) -> TextureData {
+    match client.next_event().unwrap() {
+        Event::Drawing(texture_data) => texture_data,
+        other => panic!("expected a drawing, got {other:?}")
+    }
+}
+
+#[test]
+fn drawings_reach_every_client() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    let mut alice = join(addr, "alice");
+    let mut bob = join(addr, "bob");
+
+    let sent = alice.send_drawing(canvas(3)).unwrap();
+    for client in [&mut alice, &mut bob] {
+        let received = next_drawing(client);
+        assert_eq!(display_name(&received.name), "alice");
+        assert_eq!(received.timestamp, sent.timestamp);
+        assert_eq!(received.data, canvas(3));
+        assert_eq!(received.id, 1);
+    }
+}
+
+#[test]
+fn history_is_fetched_on_join() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    let mut alice = join(addr, "alice");
+    alice.send_drawing(canvas(1)).unwrap();
+    alice.send_drawing(canvas(2)).unwrap();
+    next_drawing(&mut alice);
+    next_drawing(&mut alice);
+
+    let mut bob = Client::connect(addr, "bob").unwrap();
+    let history = bob.fetch_history().unwrap();
+    assert_eq!(history.iter().map(|item| item.id).collect::<Vec<_>>(), [1, 2]);
+    assert_eq!(history[1].data, canvas(2));
+}
+
+#[test]
+fn only_the_author_can_delete() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    let mut alice = join(addr, "alice");
+    let mut bob = join(addr, "bob");
+    let id = {
+        alice.send_drawing(canvas(5)).unwrap();
+        next_drawing(&mut bob);
+        next_drawing(&mut alice).id
+    };
+
+    bob.delete(id).unwrap();
+    assert!(matches!(bob.next_event().unwrap(), Event::DeleteRefused(refused) if refused == id));
+
+    alice.delete(id).unwrap();
+    assert!(matches!(alice.next_event().unwrap(), Event::Deleted(deleted) if deleted == id));
+    assert!(matches!(bob.next_event().unwrap(), Event::Deleted(deleted) if deleted == id));
+}
+
+#[test]
+fn the_same_key_can_delete_after_reconnecting() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    let key = psrs_client::new_key();
+    let mut alice = Client::connect(addr, "alice").unwrap();
+    alice.set_key(key);
+    alice.fetch_history().unwrap();
+    alice.send_drawing(canvas(5)).unwrap();
+    let id = next_drawing(&mut alice).id;
+    alice.sender().disconnect();
+
+    // Someone else under her name can't, she can without drawing again
+    let mut impostor = join(addr, "alice");
+    impostor.delete(id).unwrap();
+    assert!(matches!(impostor.next_event().unwrap(), Event::DeleteRefused(refused) if refused == id));
+    let mut alice = Client::connect(addr, "alice").unwrap();
+    alice.set_key(key);
+    alice.set_read_timeout(Some(TIMEOUT)).unwrap();
+    alice.fetch_history().unwrap();
+    alice.delete(id).unwrap();
+    assert!(matches!(alice.next_event().unwrap(), Event::Deleted(deleted) if deleted == id));
+}
+
+#[test]
+fn subscribers_get_events_on_a_channel() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    let (alice, events) = join(addr, "alice").subscribe();
+    let bob = join(addr, "bob");
+
+    bob.send_drawing(canvas(9)).unwrap();
+    match events.recv_timeout(TIMEOUT).unwrap().unwrap() {
+        Event::Drawing(texture_data) => assert_eq!(display_name(&texture_data.name), "bob"),
+        other => panic!("expected a drawing, got {other:?}")
+    }
+
+    // Disconnecting ends the reader thread with an error
+    alice.disconnect();
+    assert!(events.recv_timeout(TIMEOUT).unwrap().is_err());
+    assert!(events.recv_timeout(TIMEOUT).is_err());
+}
+
+#[test]
+fn history_can_be_fetched_again_while_drawings_arrive() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    let mut alice = join(addr, "alice");
+    let bob = join(addr, "bob");
+
+    // Bob's drawing reaches alice before her second history does and is kept for later
+    bob.send_drawing(canvas(4)).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let history = alice.fetch_history().unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(display_name(&next_drawing(&mut alice).name), "bob");
+}
+
+#[test]
+fn replies_name_the_drawing_they_answer() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    let mut alice = join(addr, "alice");
+    let mut bob = join(addr, "bob");
+    alice.send_drawing(canvas(1)).unwrap();
+    let original = next_drawing(&mut bob);
+    next_drawing(&mut alice);
+
+    let sent = bob.send_reply(canvas(2), original.id).unwrap();
+    assert_eq!(sent.reply_to, original.id);
+    let reply = next_drawing(&mut alice);
+    assert_eq!((reply.id, reply.reply_to), (2, original.id));
+}
+
+#[test]
+fn reactions_are_totalled_for_everyone() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    let mut alice = join(addr, "alice");
+    let mut bob = join(addr, "bob");
+    alice.send_drawing(canvas(1)).unwrap();
+    let drawing = next_drawing(&mut bob);
+    next_drawing(&mut alice);
+
+    // Bob said who he is on joining, so he needn't have drawn anything himself
+    bob.react(drawing.id, Reaction::Wow).unwrap();
+    for client in [&mut alice, &mut bob] {
+        match client.next_event().unwrap() {
+            Event::Reactions(counts) => {
+                assert_eq!(counts.message_id, drawing.id);
+                assert_eq!(counts.count(Reaction::Wow), 1);
+            },
+            other => panic!("expected reactions, got {other:?}")
+        }
+    }
+
+    // Reactions so far come straight after the history
+    let mut carol = join(addr, "carol");
+    assert!(matches!(carol.next_event().unwrap(), Event::Reactions(counts) if counts.count(Reaction::Wow) == 1));
+}
+
+#[test]
+fn activity_is_passed_on_to_everyone_else() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    let mut alice = join(addr, "alice");
+    let mut bob = join(addr, "bob");
+    // Once this reaches alice the server has bob down as joined
+    bob.send_drawing(canvas(1)).unwrap();
+    next_drawing(&mut alice);
+    next_drawing(&mut bob);
+
+    // Alice said who she is on joining, so she needn't have drawn anything yet
+    alice.activity().unwrap();
+    assert!(matches!(bob.next_event().unwrap(), Event::Activity(name) if name == "alice"));
+
+    // Alice isn't told about her own, so her next event is bob's drawing
+    bob.send_drawing(canvas(2)).unwrap();
+    assert_eq!(display_name(&next_drawing(&mut alice).name), "bob");
+}
+
+#[test]
+fn the_shared_canvas_is_drawn_on_together_then_posted() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    let mut alice = join(addr, "alice");
+    let mut bob = join(addr, "bob");
+    let line = Stroke { from: (10, 10), to: (190, 40), pen: Pen::Thin, value: 80 };
+
+    alice.sender().join_board().unwrap();
+    assert!(matches!(alice.next_event().unwrap(), Event::Board(board) if board == canvas(BACKGROUND)));
+    bob.sender().join_board().unwrap();
+    assert!(matches!(bob.next_event().unwrap(), Event::Board(_)));
+    alice.sender().stroke(&line).unwrap();
+    assert!(matches!(bob.next_event().unwrap(), Event::Stroke(received) if received == line));
+
+    bob.sender().post_board().unwrap();
+    assert!(matches!(alice.next_event().unwrap(), Event::BoardCleared));
+    let posted = next_drawing(&mut alice);
+    assert_eq!(display_name(&posted.name), "bob");
+    let mut expected = canvas(BACKGROUND);
+    draw_stroke(&mut expected, &line);
+    assert_eq!(posted.data, expected);
+}
+
+#[test]
+fn bad_names_and_canvases_are_refused_locally() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    assert!(matches!(Client::connect(addr, "a name that is far too long to fit"), Err(Error::InvalidName(_))));
+    assert!(matches!(Client::connect(addr, ""), Err(Error::InvalidName(_))));
+
+    let alice = join(addr, "alice");
+    assert!(matches!(alice.send_drawing(vec![0; 10]), Err(Error::Protocol(_))));
+}
+
+#[test]
+fn drawings_can_be_resent_over_a_stream_opened_elsewhere() {
+    let server = TestServer::start();
+    let addr = server.addr;
+    let stream = std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(5)).unwrap();
+    let mut alice = Client::from_stream(stream, "alice").unwrap();
+    alice.set_read_timeout(Some(TIMEOUT)).unwrap();
+    alice.fetch_history().unwrap();
+
+    let mut queued = alice.send_drawing(canvas(6)).unwrap();
+    next_drawing(&mut alice);
+    queued.data = canvas(7);
+    alice.sender().resend(&queued).unwrap();
+    let received = next_drawing(&mut alice);
+    assert_eq!(display_name(&received.name), "alice");
+    assert_eq!(received.timestamp, queued.timestamp);
+    assert_eq!(received.data, canvas(7));
+
+    queued.data.truncate(10);
+    assert!(matches!(alice.sender().resend(&queued), Err(Error::Protocol(_))));
+}
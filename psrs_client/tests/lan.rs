@@ -0,0 +1,121 @@
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+use psrs_client::lan::LanPeer;
+use psrs_client::{Error, Event};
+use psrs_protocol::*;
+
+// A group of our own per test, on a port nothing else is using
+fn group() -> SocketAddrV4 {
+    let port = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap().local_addr().unwrap().port();
+    SocketAddrV4::new(Ipv4Addr::new(239, 255, 69, 70), port)
+}
+
+fn join(group: SocketAddrV4, name: &str) -> LanPeer {
+    let peer = LanPeer::join(group, name).unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    peer
+}
+
+fn next_drawing(peer: &mut LanPeer) -> TextureData {
+    match peer.next_event().unwrap() {
+        Event::Drawing(texture_data) => texture_data,
+        other => panic!("expected a drawing, got {other:?}")
+    }
+}
+
+#[test]
+fn drawings_reach_every_peer_including_the_sender() {
+    let group = group();
+    let mut alice = join(group, "alice");
+    let mut bob = join(group, "bob");
+
+    let sent = alice.send_drawing(vec![9; CANVAS_SIZE]).unwrap();
+    for peer in [&mut alice, &mut bob] {
+        let received = next_drawing(peer);
+        assert_eq!(display_name(&received.name), "alice");
+        assert_eq!(received.data, sent.data);
+        assert_eq!(received.timestamp, sent.timestamp);
+        assert_eq!(received.id, 1);
+    }
+}
+
+#[test]
+fn peers_number_drawings_as_they_arrive() {
+    let group = group();
+    let alice = join(group, "alice");
+    let mut bob = join(group, "bob");
+
+    alice.send_drawing(vec![1; CANVAS_SIZE]).unwrap();
+    bob.send_drawing(vec![2; CANVAS_SIZE]).unwrap();
+    let ids: Vec<i32> = (0..2).map(|_| next_drawing(&mut bob).id).collect();
+    assert_eq!(ids, [1, 2]);
+
+    // So a reply can't say which of them it answers
+    let mut reply = alice.send_drawing(vec![3; CANVAS_SIZE]).unwrap();
+    reply.reply_to = 1;
+    alice.resend(&reply).unwrap();
+    assert_eq!(next_drawing(&mut bob).reply_to, 0);
+    assert_eq!(next_drawing(&mut bob).reply_to, 0);
+}
+
+#[test]
+fn junk_and_other_messages_are_ignored() {
+    let group = group();
+    let alice = join(group, "alice");
+    let mut bob = join(group, "bob");
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+    socket.send_to(b"not a drawing", group).unwrap();
+    socket.send_to(&InfoData::new(InfoMsg::Kicked, 0).to_bytes(), group).unwrap();
+    let mut truncated = InfoData::new(InfoMsg::Drawing, PACKET_SIZE as i32).to_bytes();
+    truncated.extend(vec![0; 100]);
+    socket.send_to(&truncated, group).unwrap();
+
+    alice.send_drawing(vec![3; CANVAS_SIZE]).unwrap();
+    let received = next_drawing(&mut bob);
+    assert_eq!(display_name(&received.name), "alice");
+    assert_eq!(received.id, 1);
+}
+
+#[test]
+fn invalid_names_are_refused() {
+    assert!(matches!(LanPeer::join(group(), ""), Err(Error::InvalidName(_))));
+}
+
+#[test]
+fn resent_drawings_keep_their_name_and_timestamp() {
+    let group = group();
+    let alice = join(group, "alice");
+    let mut bob = join(group, "bob");
+
+    let mut queued = alice.send_drawing(vec![4; CANVAS_SIZE]).unwrap();
+    assert_eq!(next_drawing(&mut bob).timestamp, queued.timestamp);
+
+    queued.data = vec![5; CANVAS_SIZE];
+    alice.resend(&queued).unwrap();
+    let received = next_drawing(&mut bob);
+    assert_eq!(display_name(&received.name), "alice");
+    assert_eq!(received.timestamp, queued.timestamp);
+    assert_eq!(received.data, queued.data);
+
+    queued.data.truncate(10);
+    assert!(matches!(alice.resend(&queued), Err(Error::Protocol(_))));
+}
+
+#[test]
+fn activity_reaches_the_other_peers() {
+    let group = group();
+    let alice = join(group, "alice");
+    let mut bob = join(group, "bob");
+    let mut carol = join(group, "carol");
+
+    alice.activity().unwrap();
+    for peer in [&mut bob, &mut carol] {
+        assert!(matches!(peer.next_event().unwrap(), Event::Activity(name) if name == "alice"));
+    }
+
+    // Bob's own activity isn't reported back to him, so the next thing he hears is the drawing
+    bob.activity().unwrap();
+    alice.send_drawing(vec![6; CANVAS_SIZE]).unwrap();
+    assert_eq!(display_name(&next_drawing(&mut bob).name), "alice");
+}
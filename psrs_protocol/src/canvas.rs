@@ -0,0 +1,122 @@
+use std::io::Cursor;
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, ImageFormat, Luma};
+use serde::{Deserialize, Serialize};
+use crate::CANVAS_SIZE;
+
+pub const CANVAS_WIDTH: u32 = 200;
+pub const CANVAS_HEIGHT: u32 = 200;
+
+// Untouched pixels on a canvas
+pub const BACKGROUND: u8 = 127;
+
+// Same shading as the client's history view (value v shows as 1 - v), except the
+// untouched background comes out white instead of mid grey.
+pub fn canvas_image(data: &[u8]) -> GrayImage {
+    GrayImage::from_fn(CANVAS_WIDTH, CANVAS_HEIGHT, |x, y| {
+        let value = data.get((y * CANVAS_WIDTH + x) as usize).copied().unwrap_or(BACKGROUND);
+        Luma([if value == BACKGROUND { 255 } else { 255 - value }])
+    })
+}
+
+pub fn canvas_png(data: &[u8]) -> Vec<u8> {
+    let mut png = Vec::new();
+    canvas_image(data)
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .expect("encoding to memory can't fail");
+    png
+}
+
+// Turns an arbitrary picture into canvas data: scaled to fit 200x200 keeping its
+// aspect ratio, centred, and stored inverted the way the client's shader expects
+// (0 shows white, 255 black). Transparent pixels and the margins are left as
+// background.
+pub fn image_to_canvas(image: &DynamicImage) -> Vec<u8> {
+    let scaled = image.resize(CANVAS_WIDTH, CANVAS_HEIGHT, FilterType::Triangle).into_luma_alpha8();
+    let left = (CANVAS_WIDTH - scaled.width()) / 2;
+    let top = (CANVAS_HEIGHT - scaled.height()) / 2;
+
+    let mut canvas = vec![BACKGROUND; CANVAS_SIZE];
+    for (x, y, pixel) in scaled.enumerate_pixels() {
+        let [grey, alpha] = pixel.0;
+        if alpha < 128 {
+            continue;
+        }
+        // A pixel that happens to land on the background value would vanish, so nudge it
+        let value = match 255 - grey {
+            BACKGROUND => BACKGROUND - 1,
+            value => value
+        };
+        canvas[((top + y) * CANVAS_WIDTH + left + x) as usize] = value;
+    }
+    canvas
+}
+
+// Pen shapes: the pixels around the pen's position that a stroke colours in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pen {
+    Huge,
+    Fat,
+    Thin,
+    Tiny
+}
+
+impl Pen {
+    pub fn spots(&self) -> &'static [(i8, i8)] {
+        match self {
+            Pen::Huge => &[
+                (0, 0), (1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (-1, 1), (1, -1),
+                (-1, -1), (-1, 2), (0, 2), (1, 2), (-1, -2), (0, -2), (1, -2), (2, -1),
+                (2, 0), (2, 1), (-2, -1), (-2, 0), (-2, 1), (-3, -2), (-3, -1), (-3, 0),
+                (-3, 1), (-3, 2), (3, -2), (3, -1), (3, 0), (3, 1), (3, 2), (-2, 3),
+                (-1, 3), (0, 3), (1, 3), (2, 3), (-2, -3), (-1, -3), (0, -3), (1, -3),
+                (2, -3), (-2, -2), (2, -2), (2, 2), (-2, 2),
+            ],
+            Pen::Fat => &[
+                (0, 0), (1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (-1, 1), (1, -1),
+                (-1, -1), (-1, 2), (0, 2), (1, 2), (-1, -2), (0, -2), (1, -2), (2, -1),
+                (2, 0), (2, 1), (-2, -1), (-2, 0), (-2, 1),
+            ],
+            Pen::Thin => &[
+                (0, 0), (1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (-1, 1), (1, -1),
+                (-1, -1),
+            ],
+            Pen::Tiny => &[
+                (0, 0),
+            ]
+        }
+    }
+}
+
+// One frame's worth of pen movement, in canvas pixels (0 to 200 inclusive). Strokes
+// are what a shared canvas is drawn with, so everyone applying the same ones in the
+// same order ends up with the same picture.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Stroke {
+    pub from: (u8, u8),
+    pub to: (u8, u8),
+    pub pen: Pen,
+    pub value: u8
+}
+
+// How many pen positions a stroke is filled in with, from its start up to (not
+// including) its end, where the next stroke carries on
+const STROKE_STEPS: u8 = 15;
+
+pub fn draw_stroke(canvas: &mut [u8], stroke: &Stroke) {
+    let Some(max) = canvas.len().checked_sub(1) else {
+        return;
+    };
+    let max = max as i32;
+    let width = CANVAS_WIDTH as i32;
+    for step in 0..STROKE_STEPS {
+        let t = step as f32 / STROKE_STEPS as f32;
+        let x = (stroke.from.0 as f32 + (stroke.to.0 as f32 - stroke.from.0 as f32) * t) as i32;
+        let y = (stroke.from.1 as f32 + (stroke.to.1 as f32 - stroke.from.1 as f32) * t) as i32;
+        let center = (y * width + x).clamp(0, max);
+        for (dx, dy) in stroke.pen.spots() {
+            let index = center + *dx as i32 + *dy as i32 * width;
+            canvas[index.clamp(0, max) as usize] = stroke.value;
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use serde::{Serialize, Deserialize};
+use crate::decode;
+
+// Clients broadcast a query to this UDP port and servers answer with a ServerInfo
+pub const DISCOVERY_PORT: u16 = 6968;
+pub const MAX_SERVER_NAME_SIZE: usize = 64;
+
+const QUERY_MAGIC: &[u8; 8] = b"PSRSDISQ";
+const REPLY_MAGIC: &[u8; 8] = b"PSRSDISR";
+// The id, the name with its length prefix, then the port and the two counts
+const MAX_REPLY_SIZE: usize = REPLY_MAGIC.len() + 8 + 8 + MAX_SERVER_NAME_SIZE + 2 + 4 + 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    // Random per server run; the same server can answer from more than one address
+    pub id: u64,
+    pub name: String,
+    // The TCP port clients connect to; the address is wherever the reply came from
+    pub port: u16,
+    pub clients: u32,
+    pub messages: u32
+}
+
+pub fn query() -> Vec<u8> {
+    QUERY_MAGIC.to_vec()
+}
+
+pub fn is_query(bytes: &[u8]) -> bool {
+    bytes == QUERY_MAGIC
+}
+
+// Names longer than MAX_SERVER_NAME_SIZE are cut short
+pub fn reply(info: &ServerInfo) -> Vec<u8> {
+    let mut end = info.name.len().min(MAX_SERVER_NAME_SIZE);
+    while !info.name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let info = ServerInfo { name: info.name[..end].to_string(), ..info.clone() };
+    let mut packet = REPLY_MAGIC.to_vec();
+    packet.extend(bincode::serialize(&info).unwrap());
+    packet
+}
+
+// None for anything that isn't a well-formed reply, since anyone can send us datagrams
+pub fn decode_reply(bytes: &[u8]) -> Option<ServerInfo> {
+    let payload = bytes.strip_prefix(REPLY_MAGIC)?;
+    decode(payload, MAX_REPLY_SIZE).ok()
+}
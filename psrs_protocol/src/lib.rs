@@ -0,0 +1,488 @@
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use bincode::Options;
+use std::time::{SystemTime, UNIX_EPOCH};
+use canvas::{Stroke, CANVAS_WIDTH, CANVAS_HEIGHT};
+
+pub mod canvas;
+pub mod discovery;
+#[cfg(feature = "logging")]
+pub mod logging;
+
+pub const PACKET_SIZE: usize = 40063;
+pub const INFO_SIZE: usize = 8;
+pub const CANVAS_SIZE: usize = 200 * 200;
+pub const NAME_SIZE: usize = 24;
+pub const MAX_ANNOUNCEMENT_SIZE: usize = 256;
+pub const MAX_RELAY_KEY_SIZE: usize = 64;
+// Server id, then the key with its length prefix
+pub const MAX_RELAY_HELLO_SIZE: usize = 8 + 8 + MAX_RELAY_KEY_SIZE;
+// Origin server id, then the drawing
+pub const RELAY_DRAWING_SIZE: usize = 8 + PACKET_SIZE;
+// Two points, the pen and the value
+pub const STROKE_SIZE: usize = 2 + 2 + 4 + 1;
+// Message id, reaction and name
+pub const REACT_SIZE: usize = 4 + 4;
+// Message id and a count for each kind of reaction
+pub const REACTION_COUNTS_SIZE: usize = 4 + 4 * Reaction::ALL.len();
+pub const KEY_SIZE: usize = 16;
+// Name and key
+pub const HELLO_SIZE: usize = NAME_SIZE + KEY_SIZE;
+
+// Drawings stamped before 2020 or more than five minutes ahead of us are rejected
+const MIN_TIMESTAMP: u128 = 1_577_836_800_000;
+const MAX_CLOCK_SKEW: u128 = 5 * 60 * 1000;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TextureData {
+    pub name: [u8; 24],
+    pub data: Vec<u8>,
+    pub request_history: bool,
+    pub request_history_length: bool,
+    pub history_length: i32,
+    pub confirm_history: bool,
+    pub timestamp: u128,
+    pub id: i32,
+    // The id of the drawing this one answers, or 0 if it isn't a reply
+    pub reply_to: i32
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum InfoMsg {
+    RequestHistoryLength,
+    HistoryLength,
+    RequestHistory,
+    ConfirmReceivedHistory,
+    Nothing,
+    Drawing,
+    DeleteMessage,
+    MessageDeleted,
+    DeleteRefused,
+    Error,
+    ServerShutdown,
+    Announcement,
+    Kicked,
+    // Only sent between relaying servers
+    RelayHello,
+    RelayDrawing,
+    // Someone is drawing or typing; see activity()
+    Activity,
+    // The shared canvas: clients join and leave it, get the whole of it as Board
+    // when they join, then Stroke and ClearBoard as they happen. PostBoard puts it
+    // in the history under the name sent with it, which has to be the one the
+    // client draws under, and starts a fresh one.
+    JoinBoard,
+    LeaveBoard,
+    Board,
+    Stroke,
+    ClearBoard,
+    PostBoard,
+    // A client stamps a reaction on a message (or takes it back) with React, and
+    // everyone is sent the message's new totals as ReactionCounts
+    React,
+    ReactionCounts,
+    // A drawing the server wouldn't take. They're answered in the order they were
+    // sent, so it's the oldest the client is still waiting on; number is why, as a
+    // ProtocolError code, or 0 if a plugin turned it away (and said why itself).
+    DrawingRefused,
+    // Who the client is, sent before the history handshake; see Hello
+    Hello
+}
+
+// Every message on the wire starts with one of these. For messages that carry
+// a payload (Drawing, Announcement, Activity, the board, reaction and relay messages),
+// number is the payload length in bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct InfoData {
+    pub msg: InfoMsg,
+    pub number: i32
+}
+
+// Sent back to the client as InfoMsg::Error with the code in number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    Malformed = 1,
+    UnknownMessage = 2,
+    UnexpectedMessage = 3,
+    BadCanvasSize = 4,
+    BadName = 5,
+    BadTimestamp = 6,
+    TooLarge = 7
+}
+
+impl ProtocolError {
+    pub const ALL: [ProtocolError; 7] = [
+        ProtocolError::Malformed,
+        ProtocolError::UnknownMessage,
+        ProtocolError::UnexpectedMessage,
+        ProtocolError::BadCanvasSize,
+        ProtocolError::BadName,
+        ProtocolError::BadTimestamp,
+        ProtocolError::TooLarge
+    ];
+
+    // Framing errors leave the stream in an unknown state, so the connection can't continue
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, ProtocolError::Malformed | ProtocolError::UnknownMessage | ProtocolError::TooLarge)
+    }
+
+    pub fn from_code(code: i32) -> Option<ProtocolError> {
+        ProtocolError::ALL.into_iter().find(|error| *error as i32 == code)
+    }
+
+    pub fn reply(&self) -> InfoData {
+        InfoData {
+            msg: InfoMsg::Error,
+            number: *self as i32
+        }
+    }
+}
+
+impl InfoData {
+    pub fn new(msg: InfoMsg, number: i32) -> InfoData {
+        InfoData {
+            msg,
+            number
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+}
+
+// Same encoding as bincode::deserialize, but refuses to decode (or allocate for)
+// anything bigger than limit bytes. bincode ignores the limit when handed a slice,
+// so the bytes go through the reader path.
+pub fn decode<T: DeserializeOwned>(mut bytes: &[u8], limit: usize) -> Result<T, bincode::Error> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit as u64)
+        .deserialize_from(&mut bytes)
+}
+
+fn decode_error(e: &bincode::ErrorKind, otherwise: ProtocolError) -> ProtocolError {
+    match e {
+        bincode::ErrorKind::SizeLimit => ProtocolError::TooLarge,
+        _ => otherwise
+    }
+}
+
+pub fn decode_header(bytes: &[u8]) -> Result<InfoData, ProtocolError> {
+    if bytes.len() != INFO_SIZE {
+        return Err(ProtocolError::Malformed);
+    }
+    decode(bytes, INFO_SIZE).map_err(|e| decode_error(&e, ProtocolError::UnknownMessage))
+}
+
+// How many payload bytes follow a header the client sent, or why we won't read them
+pub fn inbound_payload_len(header: &InfoData) -> Result<usize, ProtocolError> {
+    match header.msg {
+        InfoMsg::Drawing => {
+            if header.number > PACKET_SIZE as i32 {
+                return Err(ProtocolError::TooLarge);
+            }
+            if header.number != PACKET_SIZE as i32 {
+                return Err(ProtocolError::Malformed);
+            }
+            Ok(PACKET_SIZE)
+        },
+        InfoMsg::RelayHello => {
+            if header.number > MAX_RELAY_HELLO_SIZE as i32 {
+                return Err(ProtocolError::TooLarge);
+            }
+            if header.number < 0 {
+                return Err(ProtocolError::Malformed);
+            }
+            Ok(header.number as usize)
+        },
+        InfoMsg::RelayDrawing => {
+            if header.number > RELAY_DRAWING_SIZE as i32 {
+                return Err(ProtocolError::TooLarge);
+            }
+            if header.number != RELAY_DRAWING_SIZE as i32 {
+                return Err(ProtocolError::Malformed);
+            }
+            Ok(RELAY_DRAWING_SIZE)
+        },
+        InfoMsg::Activity | InfoMsg::PostBoard => exact_payload_len(header, NAME_SIZE),
+        InfoMsg::Stroke => exact_payload_len(header, STROKE_SIZE),
+        InfoMsg::React => exact_payload_len(header, REACT_SIZE),
+        InfoMsg::Hello => exact_payload_len(header, HELLO_SIZE),
+        InfoMsg::RequestHistoryLength |
+        InfoMsg::RequestHistory |
+        InfoMsg::ConfirmReceivedHistory |
+        InfoMsg::DeleteMessage |
+        InfoMsg::JoinBoard |
+        InfoMsg::LeaveBoard |
+        InfoMsg::ClearBoard |
+        // From a relay peer, about something we relayed; the server won't take one from a client
+        InfoMsg::Error |
+        InfoMsg::Nothing => Ok(0),
+        InfoMsg::HistoryLength |
+        InfoMsg::MessageDeleted |
+        InfoMsg::DeleteRefused |
+        InfoMsg::ServerShutdown |
+        InfoMsg::Announcement |
+        InfoMsg::Board |
+        InfoMsg::ReactionCounts |
+        InfoMsg::DrawingRefused |
+        InfoMsg::Kicked => Err(ProtocolError::UnexpectedMessage)
+    }
+}
+
+fn exact_payload_len(header: &InfoData, len: usize) -> Result<usize, ProtocolError> {
+    if header.number > len as i32 {
+        return Err(ProtocolError::TooLarge);
+    }
+    if header.number != len as i32 {
+        return Err(ProtocolError::Malformed);
+    }
+    Ok(len)
+}
+
+// Header and UTF-8 text of a system announcement, cut down to MAX_ANNOUNCEMENT_SIZE
+pub fn announcement(text: &str) -> Vec<u8> {
+    let mut end = text.len().min(MAX_ANNOUNCEMENT_SIZE);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut packet = InfoData::new(InfoMsg::Announcement, end as i32).to_bytes();
+    packet.extend_from_slice(&text.as_bytes()[..end]);
+    packet
+}
+
+// Says name is busy on something to send. Clients send it while drawing or typing
+// and servers pass it on to everyone else as it is; nobody stores it.
+pub fn activity(name: &[u8; NAME_SIZE]) -> Vec<u8> {
+    let mut packet = InfoData::new(InfoMsg::Activity, NAME_SIZE as i32).to_bytes();
+    packet.extend_from_slice(name);
+    packet
+}
+
+pub fn decode_activity(bytes: &[u8]) -> Result<[u8; NAME_SIZE], ProtocolError> {
+    let name: [u8; NAME_SIZE] = bytes.try_into().map_err(|_| ProtocolError::Malformed)?;
+    validate_name(&name)?;
+    Ok(name)
+}
+
+// The whole shared canvas, for a client that has just joined it
+pub fn board(data: &[u8]) -> Vec<u8> {
+    let mut packet = InfoData::new(InfoMsg::Board, data.len() as i32).to_bytes();
+    packet.extend_from_slice(data);
+    packet
+}
+
+pub fn stroke(stroke: &Stroke) -> Vec<u8> {
+    let mut packet = InfoData::new(InfoMsg::Stroke, STROKE_SIZE as i32).to_bytes();
+    packet.extend(bincode::serialize(stroke).unwrap());
+    packet
+}
+
+// Strokes that start or end off the canvas are refused rather than clamped, so
+// every copy of the board stays the same
+pub fn decode_stroke(bytes: &[u8]) -> Result<Stroke, ProtocolError> {
+    if bytes.len() != STROKE_SIZE {
+        return Err(ProtocolError::Malformed);
+    }
+    let stroke: Stroke = decode(bytes, STROKE_SIZE).map_err(|e| decode_error(&e, ProtocolError::Malformed))?;
+    let on_canvas = |(x, y): (u8, u8)| x as u32 <= CANVAS_WIDTH && y as u32 <= CANVAS_HEIGHT;
+    if !on_canvas(stroke.from) || !on_canvas(stroke.to) {
+        return Err(ProtocolError::Malformed);
+    }
+    Ok(stroke)
+}
+
+// Puts the shared canvas in the history as name's drawing
+pub fn post_board(name: &[u8; NAME_SIZE]) -> Vec<u8> {
+    let mut packet = InfoData::new(InfoMsg::PostBoard, NAME_SIZE as i32).to_bytes();
+    packet.extend_from_slice(name);
+    packet
+}
+
+// Carries nothing but the name, like activity
+pub fn decode_post_board(bytes: &[u8]) -> Result<[u8; NAME_SIZE], ProtocolError> {
+    decode_activity(bytes)
+}
+
+// The stamps a message can be reacted to with. Each is drawn with one of the
+// font's glyphs, so none of them needs any artwork of its own.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum Reaction {
+    Plus,
+    Star,
+    Wow,
+    Huh
+}
+
+impl Reaction {
+    pub const ALL: [Reaction; 4] = [Reaction::Plus, Reaction::Star, Reaction::Wow, Reaction::Huh];
+
+    pub fn glyph(&self) -> u8 {
+        match self {
+            Reaction::Plus => b'+',
+            Reaction::Star => b'*',
+            Reaction::Wow => b'!',
+            Reaction::Huh => b'?'
+        }
+    }
+}
+
+// Stamping a reaction on a message. Sending the same one again takes it back.
+// It's counted under the name the client said hello with, so it carries none of its own.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct React {
+    pub message_id: i32,
+    pub reaction: Reaction
+}
+
+pub fn react(react: &React) -> Vec<u8> {
+    let mut packet = InfoData::new(InfoMsg::React, REACT_SIZE as i32).to_bytes();
+    packet.extend(bincode::serialize(react).unwrap());
+    packet
+}
+
+pub fn decode_react(bytes: &[u8]) -> Result<React, ProtocolError> {
+    if bytes.len() != REACT_SIZE {
+        return Err(ProtocolError::Malformed);
+    }
+    decode(bytes, REACT_SIZE).map_err(|e| decode_error(&e, ProtocolError::Malformed))
+}
+
+// How many of each reaction a message has, in the order of Reaction::ALL
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct ReactionCounts {
+    pub message_id: i32,
+    pub counts: [u32; Reaction::ALL.len()]
+}
+
+impl ReactionCounts {
+    pub fn count(&self, reaction: Reaction) -> u32 {
+        Reaction::ALL.iter().position(|r| *r == reaction).map_or(0, |index| self.counts[index])
+    }
+}
+
+pub fn reaction_counts(counts: &ReactionCounts) -> Vec<u8> {
+    let mut packet = InfoData::new(InfoMsg::ReactionCounts, REACTION_COUNTS_SIZE as i32).to_bytes();
+    packet.extend(bincode::serialize(counts).unwrap());
+    packet
+}
+
+pub fn decode_reaction_counts(bytes: &[u8]) -> Result<ReactionCounts, ProtocolError> {
+    if bytes.len() != REACTION_COUNTS_SIZE {
+        return Err(ProtocolError::Malformed);
+    }
+    decode(bytes, REACTION_COUNTS_SIZE).map_err(|e| decode_error(&e, ProtocolError::Malformed))
+}
+
+// The first thing a client sends. The name is what it draws, reacts and shows
+// activity under, and the key is a secret of its own: what it draws is only
+// its to delete while it comes back with the same key. Clients keep their key
+// between runs so a reconnect or restart doesn't cost them their messages.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct Hello {
+    pub name: [u8; NAME_SIZE],
+    pub key: [u8; KEY_SIZE]
+}
+
+pub fn hello(hello: &Hello) -> Vec<u8> {
+    let mut packet = InfoData::new(InfoMsg::Hello, HELLO_SIZE as i32).to_bytes();
+    packet.extend(bincode::serialize(hello).unwrap());
+    packet
+}
+
+pub fn decode_hello(bytes: &[u8]) -> Result<Hello, ProtocolError> {
+    if bytes.len() != HELLO_SIZE {
+        return Err(ProtocolError::Malformed);
+    }
+    let hello: Hello = decode(bytes, HELLO_SIZE).map_err(|e| decode_error(&e, ProtocolError::Malformed))?;
+    validate_name(&hello.name)?;
+    Ok(hello)
+}
+
+// The first thing each side of a relay link sends. Servers only relay with
+// peers that know the same key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RelayHello {
+    pub server_id: u64,
+    pub key: String
+}
+
+pub fn relay_hello(hello: &RelayHello) -> Vec<u8> {
+    let payload = bincode::serialize(hello).unwrap();
+    let mut packet = InfoData::new(InfoMsg::RelayHello, payload.len() as i32).to_bytes();
+    packet.extend(payload);
+    packet
+}
+
+pub fn decode_relay_hello(bytes: &[u8]) -> Result<RelayHello, ProtocolError> {
+    decode(bytes, MAX_RELAY_HELLO_SIZE).map_err(|e| decode_error(&e, ProtocolError::Malformed))
+}
+
+// A drawing passed between servers. Its id is the one given by the server it
+// was drawn on, so together with that server's id it names the drawing everywhere.
+pub fn relay_drawing(origin: u64, drawing: &TextureData) -> Vec<u8> {
+    let mut packet = InfoData::new(InfoMsg::RelayDrawing, RELAY_DRAWING_SIZE as i32).to_bytes();
+    packet.extend(bincode::serialize(&origin).unwrap());
+    packet.extend(bincode::serialize(drawing).unwrap());
+    packet
+}
+
+pub fn decode_relay_drawing(bytes: &[u8], now: u128) -> Result<(u64, TextureData), ProtocolError> {
+    if bytes.len() < 8 {
+        return Err(ProtocolError::Malformed);
+    }
+    let origin = decode(&bytes[..8], 8).map_err(|e| decode_error(&e, ProtocolError::Malformed))?;
+    Ok((origin, decode_drawing(&bytes[8..], now)?))
+}
+
+pub fn decode_drawing(bytes: &[u8], now: u128) -> Result<TextureData, ProtocolError> {
+    let texture_data: TextureData = decode(bytes, PACKET_SIZE).map_err(|e| decode_error(&e, ProtocolError::Malformed))?;
+    if texture_data.data.len() != CANVAS_SIZE {
+        return Err(ProtocolError::BadCanvasSize);
+    }
+    validate_name(&texture_data.name)?;
+    if texture_data.reply_to < 0 {
+        return Err(ProtocolError::Malformed);
+    }
+    if texture_data.timestamp < MIN_TIMESTAMP || texture_data.timestamp > now + MAX_CLOCK_SKEW {
+        return Err(ProtocolError::BadTimestamp);
+    }
+    Ok(texture_data)
+}
+
+// Names are UTF-8 padded with trailing zeroes. Older clients leave the newline
+// from stdin on the end, so trailing whitespace is tolerated.
+pub fn validate_name(name: &[u8; NAME_SIZE]) -> Result<String, ProtocolError> {
+    let end = name.iter().position(|b| *b == 0).unwrap_or(NAME_SIZE);
+    if name[end..].iter().any(|b| *b != 0) {
+        return Err(ProtocolError::BadName);
+    }
+    let text = std::str::from_utf8(&name[..end]).map_err(|_| ProtocolError::BadName)?;
+    let trimmed = text.trim_end();
+    if trimmed.is_empty() || trimmed.chars().any(|c| c.is_control()) {
+        return Err(ProtocolError::BadName);
+    }
+    Ok(trimmed.to_string())
+}
+
+// Names go on the wire as UTF-8 padded with zeroes to NAME_SIZE bytes
+pub fn pad_name(name: &str) -> Result<[u8; NAME_SIZE], String> {
+    if name.len() > NAME_SIZE {
+        return Err(format!("name can be at most {NAME_SIZE} bytes"));
+    }
+    let mut padded = [0u8; NAME_SIZE];
+    padded[..name.len()].copy_from_slice(name.as_bytes());
+    validate_name(&padded).map_err(|_| String::from("name must be non-empty printable text"))?;
+    Ok(padded)
+}
+
+// For showing names that may not pass validate_name, e.g. from old history files
+pub fn display_name(name: &[u8; NAME_SIZE]) -> String {
+    validate_name(name).unwrap_or_else(|_| String::from_utf8_lossy(name).trim_end_matches('\0').trim_end().to_string())
+}
+
+pub fn now_millis() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
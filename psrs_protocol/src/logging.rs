@@ -0,0 +1,50 @@
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+use tracing_subscriber::field::MakeExt;
+use tracing_subscriber::fmt::format::debug_fn;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter};
+
+// Shared by the server and the desktop client so both are set up the same way.
+// Verbosity comes from PSRS_LOG (same syntax as RUST_LOG, e.g. "debug" or
+// "psrs_server=trace"), defaulting to info. If PSRS_LOG_FILE is set, everything
+// is also appended to that file without colours.
+pub fn init() {
+    let (filter, bad_filter) = match std::env::var("PSRS_LOG") {
+        Ok(spec) => match EnvFilter::try_new(&spec) {
+            Ok(filter) => (filter, None),
+            Err(e) => (EnvFilter::new("info"), Some(format!("Ignoring PSRS_LOG={spec:?}: {e}")))
+        },
+        Err(_) => (EnvFilter::new("info"), None)
+    };
+
+    let mut bad_file = None;
+    let file_layer = std::env::var("PSRS_LOG_FILE").ok().and_then(|path| {
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => Some(fmt::layer().with_ansi(false).fmt_fields(plain_fields()).with_writer(Mutex::new(file))),
+            Err(e) => {
+                bad_file = Some(format!("Can't open log file {path}: {e}"));
+                None
+            }
+        }
+    });
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .with(file_layer)
+        .init();
+
+    for problem in bad_filter.into_iter().chain(bad_file) {
+        tracing::warn!("{problem}");
+    }
+}
+
+// Formatted span fields are cached per formatter type, so the file layer needs a
+// formatter of its own or it would reuse the coloured output of the terminal layer.
+fn plain_fields() -> impl for<'w> fmt::FormatFields<'w> + 'static {
+    debug_fn(|writer, field, value| match field.name() {
+        "message" => write!(writer, "{value:?}"),
+        name => write!(writer, "{name}={value:?}")
+    }).delimited(" ")
+}
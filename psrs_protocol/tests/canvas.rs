@@ -0,0 +1,73 @@
+use std::io::Cursor;
+use image::{DynamicImage, GrayImage, ImageFormat, Luma, LumaA, GrayAlphaImage};
+use psrs_protocol::canvas::{canvas_image, draw_stroke, image_to_canvas, Pen, Stroke, BACKGROUND};
+use psrs_protocol::*;
+
+#[test]
+fn wide_images_are_letterboxed() {
+    // Black on the left half, white on the right, twice as wide as it is tall
+    let image = GrayImage::from_fn(400, 200, |x, _| Luma([if x < 200 { 0 } else { 255 }]));
+    let canvas = image_to_canvas(&DynamicImage::ImageLuma8(image));
+    assert_eq!(canvas.len(), CANVAS_SIZE);
+
+    // Scaled to 200x100 and centred, leaving background above and below
+    assert_eq!(canvas[10 * 200 + 50], BACKGROUND);
+    assert_eq!(canvas[190 * 200 + 150], BACKGROUND);
+    assert_eq!(canvas[100 * 200 + 50], 255);
+    assert_eq!(canvas[100 * 200 + 150], 0);
+}
+
+#[test]
+fn conversion_round_trips_through_the_gallery_renderer() {
+    let image = GrayImage::from_fn(200, 200, |x, y| Luma([((x + y) % 256) as u8]));
+    let rendered = canvas_image(&image_to_canvas(&DynamicImage::ImageLuma8(image.clone())));
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let grey = rendered.get_pixel(x, y).0[0] as i32;
+        assert!((grey - pixel.0[0] as i32).abs() <= 1, "pixel {x},{y} came back as {grey}, was {}", pixel.0[0]);
+    }
+}
+
+#[test]
+fn transparent_pixels_become_background() {
+    let image = GrayAlphaImage::from_fn(200, 200, |x, _| LumaA([0, if x < 100 { 0 } else { 255 }]));
+    let canvas = image_to_canvas(&DynamicImage::ImageLumaA8(image));
+    assert_eq!(canvas[50], BACKGROUND);
+    assert_eq!(canvas[150], 255);
+}
+
+#[test]
+fn jpegs_are_accepted() {
+    let image = DynamicImage::ImageLuma8(GrayImage::from_pixel(64, 64, Luma([0])));
+    let mut jpeg = Vec::new();
+    image.write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg).unwrap();
+    let canvas = image_to_canvas(&image::load_from_memory(&jpeg).unwrap());
+    assert!(canvas.iter().all(|value| *value >= 250));
+}
+
+#[test]
+fn names_are_padded_and_checked() {
+    assert_eq!(&pad_name("bob").unwrap()[..4], b"bob\0");
+    assert!(pad_name("").is_err());
+    assert!(pad_name("a\tb").is_err());
+    assert!(pad_name(&"x".repeat(NAME_SIZE + 1)).is_err());
+}
+
+#[test]
+fn strokes_colour_in_the_pen_along_the_way() {
+    let mut canvas = vec![BACKGROUND; CANVAS_SIZE];
+    draw_stroke(&mut canvas, &Stroke { from: (10, 100), to: (40, 100), pen: Pen::Thin, value: 254 });
+    // From the start up to just short of the end, three pixels tall
+    for x in 9..=38 {
+        for y in 99..=101 {
+            assert_eq!(canvas[y * 200 + x], 254, "pixel {x},{y}");
+        }
+    }
+    assert_eq!(canvas[100 * 200 + 41], BACKGROUND);
+    assert_eq!(canvas[102 * 200 + 20], BACKGROUND);
+
+    // Rubbing out is a stroke too, and the corners don't go out of bounds
+    draw_stroke(&mut canvas, &Stroke { from: (200, 200), to: (190, 190), pen: Pen::Huge, value: 0 });
+    draw_stroke(&mut canvas, &Stroke { from: (0, 0), to: (10, 10), pen: Pen::Huge, value: 0 });
+    assert_eq!(canvas[CANVAS_SIZE - 1], 0);
+    assert_eq!(canvas[0], 0);
+}
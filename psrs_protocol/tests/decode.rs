@@ -0,0 +1,216 @@
+use proptest::prelude::*;
+use psrs_protocol::*;
+use psrs_protocol::canvas::{Pen, Stroke};
+
+const NOW: u128 = 1_710_000_000_000;
+
+fn valid_drawing() -> TextureData {
+    let mut name = [0u8; NAME_SIZE];
+    name[..5].copy_from_slice(b"alice");
+    TextureData {
+        name,
+        data: vec![127; CANVAS_SIZE],
+        request_history: false,
+        request_history_length: false,
+        history_length: 0,
+        confirm_history: false,
+        timestamp: NOW,
+        id: 0,
+        reply_to: 0
+    }
+}
+
+#[test]
+fn valid_drawing_is_accepted() {
+    let bytes = bincode::serialize(&valid_drawing()).unwrap();
+    assert_eq!(bytes.len(), PACKET_SIZE);
+    let decoded = decode_drawing(&bytes, NOW).unwrap();
+    assert_eq!(validate_name(&decoded.name).unwrap(), "alice");
+}
+
+#[test]
+fn drawing_checks_each_field() {
+    let mut short = valid_drawing();
+    short.data.truncate(CANVAS_SIZE - 1);
+    assert_eq!(decode_drawing(&bincode::serialize(&short).unwrap(), NOW).unwrap_err(), ProtocolError::BadCanvasSize);
+
+    let mut unnamed = valid_drawing();
+    unnamed.name = [0; NAME_SIZE];
+    assert_eq!(decode_drawing(&bincode::serialize(&unnamed).unwrap(), NOW).unwrap_err(), ProtocolError::BadName);
+
+    let mut future = valid_drawing();
+    future.timestamp = NOW + 60 * 60 * 1000;
+    assert_eq!(decode_drawing(&bincode::serialize(&future).unwrap(), NOW).unwrap_err(), ProtocolError::BadTimestamp);
+
+    let mut ancient = valid_drawing();
+    ancient.timestamp = 0;
+    assert_eq!(decode_drawing(&bincode::serialize(&ancient).unwrap(), NOW).unwrap_err(), ProtocolError::BadTimestamp);
+
+    let mut reply = valid_drawing();
+    reply.reply_to = 3;
+    assert_eq!(decode_drawing(&bincode::serialize(&reply).unwrap(), NOW).unwrap().reply_to, 3);
+    reply.reply_to = -3;
+    assert_eq!(decode_drawing(&bincode::serialize(&reply).unwrap(), NOW).unwrap_err(), ProtocolError::Malformed);
+}
+
+#[test]
+fn names_from_older_clients_keep_their_newline() {
+    let mut name = [0u8; NAME_SIZE];
+    name[..4].copy_from_slice(b"bob\n");
+    assert_eq!(validate_name(&name).unwrap(), "bob");
+
+    name[5] = b'x';
+    assert_eq!(validate_name(&name).unwrap_err(), ProtocolError::BadName);
+}
+
+#[test]
+fn server_only_messages_are_refused() {
+    for msg in [InfoMsg::HistoryLength, InfoMsg::MessageDeleted, InfoMsg::DeleteRefused, InfoMsg::ServerShutdown, InfoMsg::Announcement, InfoMsg::Kicked, InfoMsg::DrawingRefused] {
+        assert_eq!(inbound_payload_len(&InfoData::new(msg, 0)).unwrap_err(), ProtocolError::UnexpectedMessage);
+    }
+    // Relay peers send errors back, which carry their code in the header
+    assert_eq!(inbound_payload_len(&ProtocolError::BadTimestamp.reply()).unwrap(), 0);
+    assert_eq!(inbound_payload_len(&InfoData::new(InfoMsg::Drawing, PACKET_SIZE as i32)).unwrap(), PACKET_SIZE);
+    assert_eq!(inbound_payload_len(&InfoData::new(InfoMsg::Drawing, -1)).unwrap_err(), ProtocolError::Malformed);
+}
+
+#[test]
+fn relay_messages_round_trip() {
+    let hello = RelayHello { server_id: 7, key: String::from("secret") };
+    let packet = relay_hello(&hello);
+    let header = decode_header(&packet[..INFO_SIZE]).unwrap();
+    assert_eq!(inbound_payload_len(&header).unwrap(), packet.len() - INFO_SIZE);
+    assert_eq!(decode_relay_hello(&packet[INFO_SIZE..]).unwrap(), hello);
+
+    let packet = relay_drawing(7, &valid_drawing());
+    let header = decode_header(&packet[..INFO_SIZE]).unwrap();
+    assert_eq!(inbound_payload_len(&header).unwrap(), RELAY_DRAWING_SIZE);
+    let (origin, drawing) = decode_relay_drawing(&packet[INFO_SIZE..], NOW).unwrap();
+    assert_eq!(origin, 7);
+    assert_eq!(drawing.id, valid_drawing().id);
+}
+
+#[test]
+fn oversized_relay_messages_are_refused() {
+    assert_eq!(inbound_payload_len(&InfoData::new(InfoMsg::RelayHello, MAX_RELAY_HELLO_SIZE as i32 + 1)).unwrap_err(), ProtocolError::TooLarge);
+    assert_eq!(inbound_payload_len(&InfoData::new(InfoMsg::RelayDrawing, PACKET_SIZE as i32)).unwrap_err(), ProtocolError::Malformed);
+    let long_key = RelayHello { server_id: 7, key: "k".repeat(MAX_RELAY_KEY_SIZE + 1) };
+    let packet = relay_hello(&long_key);
+    assert_eq!(decode_relay_hello(&packet[INFO_SIZE..]).unwrap_err(), ProtocolError::TooLarge);
+}
+
+#[test]
+fn activity_round_trips_and_checks_the_name() {
+    let packet = activity(&valid_drawing().name);
+    let header = decode_header(&packet[..INFO_SIZE]).unwrap();
+    assert_eq!(inbound_payload_len(&header).unwrap(), packet.len() - INFO_SIZE);
+    assert_eq!(decode_activity(&packet[INFO_SIZE..]).unwrap(), valid_drawing().name);
+
+    assert_eq!(decode_activity(&[0; NAME_SIZE]).unwrap_err(), ProtocolError::BadName);
+    assert_eq!(decode_activity(b"alice").unwrap_err(), ProtocolError::Malformed);
+    assert_eq!(inbound_payload_len(&InfoData::new(InfoMsg::Activity, 5)).unwrap_err(), ProtocolError::Malformed);
+    assert_eq!(inbound_payload_len(&InfoData::new(InfoMsg::Activity, PACKET_SIZE as i32)).unwrap_err(), ProtocolError::TooLarge);
+}
+
+#[test]
+fn hello_round_trips_and_checks_the_name() {
+    let greeting = Hello { name: valid_drawing().name, key: [7; KEY_SIZE] };
+    let packet = hello(&greeting);
+    let header = decode_header(&packet[..INFO_SIZE]).unwrap();
+    assert_eq!(inbound_payload_len(&header).unwrap(), HELLO_SIZE);
+    assert_eq!(decode_hello(&packet[INFO_SIZE..]).unwrap(), greeting);
+
+    let nameless = hello(&Hello { name: [0; NAME_SIZE], ..greeting });
+    assert_eq!(decode_hello(&nameless[INFO_SIZE..]).unwrap_err(), ProtocolError::BadName);
+    assert_eq!(decode_hello(&packet[INFO_SIZE + 1..]).unwrap_err(), ProtocolError::Malformed);
+    assert_eq!(inbound_payload_len(&InfoData::new(InfoMsg::Hello, NAME_SIZE as i32)).unwrap_err(), ProtocolError::Malformed);
+}
+
+#[test]
+fn reactions_round_trip() {
+    let stamp = React { message_id: 7, reaction: Reaction::Star };
+    let packet = react(&stamp);
+    let header = decode_header(&packet[..INFO_SIZE]).unwrap();
+    assert_eq!(inbound_payload_len(&header).unwrap(), REACT_SIZE);
+    assert_eq!(decode_react(&packet[INFO_SIZE..]).unwrap(), stamp);
+
+    let mut unknown = packet.clone();
+    unknown[INFO_SIZE + 4] = 9;
+    assert_eq!(decode_react(&unknown[INFO_SIZE..]).unwrap_err(), ProtocolError::Malformed);
+    assert_eq!(decode_react(&packet[INFO_SIZE + 1..]).unwrap_err(), ProtocolError::Malformed);
+
+    // Only servers send the totals
+    let counts = ReactionCounts { message_id: 7, counts: [0, 2, 0, 1] };
+    let packet = reaction_counts(&counts);
+    assert_eq!(packet.len(), INFO_SIZE + REACTION_COUNTS_SIZE);
+    assert_eq!(decode_reaction_counts(&packet[INFO_SIZE..]).unwrap(), counts);
+    assert_eq!(counts.count(Reaction::Star), 2);
+    assert_eq!(inbound_payload_len(&decode_header(&packet[..INFO_SIZE]).unwrap()).unwrap_err(), ProtocolError::UnexpectedMessage);
+}
+
+#[test]
+fn strokes_round_trip_and_stay_on_the_canvas() {
+    let line = Stroke { from: (0, 0), to: (200, 200), pen: Pen::Fat, value: 254 };
+    let packet = stroke(&line);
+    assert_eq!(packet.len(), INFO_SIZE + STROKE_SIZE);
+    let header = decode_header(&packet[..INFO_SIZE]).unwrap();
+    assert_eq!(inbound_payload_len(&header).unwrap(), STROKE_SIZE);
+    assert_eq!(decode_stroke(&packet[INFO_SIZE..]).unwrap(), line);
+
+    let mut off_canvas = packet[INFO_SIZE..].to_vec();
+    off_canvas[0] = 201;
+    assert_eq!(decode_stroke(&off_canvas).unwrap_err(), ProtocolError::Malformed);
+    let mut unknown_pen = packet[INFO_SIZE..].to_vec();
+    unknown_pen[4] = 9;
+    assert_eq!(decode_stroke(&unknown_pen).unwrap_err(), ProtocolError::Malformed);
+
+    assert_eq!(inbound_payload_len(&InfoData::new(InfoMsg::Board, CANVAS_SIZE as i32)).unwrap_err(), ProtocolError::UnexpectedMessage);
+    assert_eq!(inbound_payload_len(&InfoData::new(InfoMsg::PostBoard, NAME_SIZE as i32)).unwrap(), NAME_SIZE);
+}
+
+#[test]
+fn oversized_messages_are_refused_before_decoding() {
+    assert_eq!(inbound_payload_len(&InfoData::new(InfoMsg::Drawing, i32::MAX)).unwrap_err(), ProtocolError::TooLarge);
+
+    let mut huge = valid_drawing();
+    huge.data = vec![254; CANVAS_SIZE * 4];
+    assert_eq!(decode_drawing(&bincode::serialize(&huge).unwrap(), NOW).unwrap_err(), ProtocolError::TooLarge);
+
+    // A length prefix claiming far more data than was sent must not be trusted
+    let mut bytes = bincode::serialize(&valid_drawing()).unwrap();
+    bytes[NAME_SIZE..NAME_SIZE + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert_eq!(decode_drawing(&bytes, NOW).unwrap_err(), ProtocolError::TooLarge);
+}
+
+proptest! {
+    #[test]
+    fn random_headers_never_panic(bytes in proptest::collection::vec(any::<u8>(), 0..16)) {
+        if let Ok(header) = decode_header(&bytes) {
+            let _ = inbound_payload_len(&header);
+        }
+    }
+
+    #[test]
+    fn unknown_message_types_are_reported(tag in 26u32.., number in any::<i32>()) {
+        let mut bytes = tag.to_le_bytes().to_vec();
+        bytes.extend(number.to_le_bytes());
+        prop_assert_eq!(decode_header(&bytes).unwrap_err(), ProtocolError::UnknownMessage);
+    }
+
+    #[test]
+    fn random_payloads_never_panic(bytes in proptest::collection::vec(any::<u8>(), 0..PACKET_SIZE + 64)) {
+        let _ = decode_drawing(&bytes, NOW);
+    }
+
+    #[test]
+    fn corrupted_drawings_never_panic(index in 0..PACKET_SIZE, value in any::<u8>()) {
+        let mut bytes = bincode::serialize(&valid_drawing()).unwrap();
+        bytes[index] = value;
+        let _ = decode_drawing(&bytes, NOW);
+    }
+
+    #[test]
+    fn random_names_never_panic(name in proptest::array::uniform24(any::<u8>())) {
+        let _ = validate_name(&name);
+    }
+}
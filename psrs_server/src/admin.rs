@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use uuid::Uuid;
+use psrs_protocol::*;
+
+use crate::{broadcast, Client, History};
+
+const HELP: &str = "Commands:
+  clients              list connected clients
+  say <text>           broadcast a system announcement
+  kick <uuid|name>     disconnect a client
+  history              show history size
+  export <path>        write a copy of the history to path
+  shutdown             stop the server
+  help                 show this message";
+
+// Reads admin commands from stdin on its own thread. If stdin is closed
+// (running detached) the console just goes away.
+pub fn spawn_console(clients: Arc<Mutex<HashMap<Uuid, Client>>>, history: Arc<Mutex<History>>, shutting_down: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        println!("Admin console ready, type help for commands");
+        for line in std::io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break
+            };
+            let line = line.trim();
+            let (command, arg) = match line.split_once(' ') {
+                Some((command, arg)) => (command, arg.trim()),
+                None => (line, "")
+            };
+
+            match command {
+                "" => {},
+                "clients" | "list" => list_clients(&clients),
+                "say" => say(&clients, arg),
+                "kick" => kick(&clients, arg),
+                "history" => show_history(&history),
+                "export" => export(&history, arg),
+                "shutdown" => {
+                    shutting_down.store(true, Ordering::SeqCst);
+                    break;
+                },
+                "help" => println!("{HELP}"),
+                _ => println!("Unknown command {command:?}, type help for commands")
+            }
+        }
+    });
+}
+
+fn list_clients(clients: &Arc<Mutex<HashMap<Uuid, Client>>>) {
+    let clients = clients.lock().unwrap();
+    println!("{} connected", clients.len());
+    for (id, client) in clients.iter() {
+        let status = match (client.peer, client.has_history) {
+            (Some(_), _) => "  (relay)",
+            (None, true) => "",
+            (None, false) => "  (syncing)"
+        };
+        println!("  {}  {:24}  {}{}", id, client.display_name(), client.addr, status);
+    }
+}
+
+fn say(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, text: &str) {
+    if text.is_empty() {
+        println!("Usage: say <text>");
+        return;
+    }
+    let mut clients = clients.lock().unwrap();
+    broadcast(&mut clients, &announcement(text));
+    println!("Announced to {} clients", clients.values().filter(|client| client.has_history).count());
+}
+
+fn kick(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, target: &str) {
+    let mut clients = clients.lock().unwrap();
+    let matches: Vec<Uuid> = clients.iter()
+        .filter(|(id, client)| id.to_string() == target || client.display_name() == target)
+        .map(|(id, _)| *id)
+        .collect();
+
+    if matches.is_empty() {
+        println!("No client matches {target:?}");
+        return;
+    }
+    for id in matches {
+        // The handler thread sees the closed socket and cleans up after itself
+        if let Some(mut client) = clients.remove(&id) {
+            client.send(&InfoData::new(InfoMsg::Kicked, 0).to_bytes());
+            client.stream.shutdown();
+            println!("Kicked {} ({})", id, client.display_name());
+        }
+    }
+}
+
+fn show_history(history: &Arc<Mutex<History>>) {
+    let history = history.lock().unwrap();
+    let bytes = bincode::serialized_size(&history.history).unwrap_or(0);
+    println!("{} messages, {} bytes, next id {}", history.history.len(), bytes, history.next_id);
+}
+
+fn export(history: &Arc<Mutex<History>>, path: &str) {
+    if path.is_empty() {
+        println!("Usage: export <path>");
+        return;
+    }
+    match history.lock().unwrap().save(Path::new(path)) {
+        Ok(()) => println!("Exported history to {path}"),
+        Err(e) => println!("Export failed: {e}")
+    }
+}
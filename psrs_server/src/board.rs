@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use psrs_protocol::canvas::{draw_stroke, BACKGROUND};
+use psrs_protocol::*;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::metrics::{self, METRICS};
+use crate::{claim, Client};
+
+// A client's strokes past this many in a STROKE_WINDOW are dropped, like activity
+// within ACTIVITY_INTERVAL, so a runaway pen can't flood everyone on the board
+const MAX_STROKES: u32 = 240;
+const STROKE_WINDOW: Duration = Duration::from_secs(1);
+
+// The shared canvas. Clients that join it get the whole of it, then every stroke
+// anyone else draws on it, and the server keeps its own copy up to date so the
+// next one to join sees the same picture. It's kept in memory only and isn't
+// relayed to other servers.
+pub(crate) struct Board {
+    data: Mutex<Vec<u8>>
+}
+
+impl Board {
+    pub fn new() -> Board {
+        Board {
+            data: Mutex::new(vec![BACKGROUND; CANVAS_SIZE])
+        }
+    }
+
+    // The board stays locked until the client is on it, so no stroke can fall in between
+    pub fn join(&self, client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>) {
+        let data = self.data.lock().unwrap();
+        let mut clients = clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(&client_id) {
+            client.send(&board(&data));
+            client.on_board = true;
+            info!("Joined the shared canvas");
+        }
+    }
+
+    pub fn leave(&self, client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>) {
+        if let Some(client) = clients.lock().unwrap().get_mut(&client_id) {
+            client.on_board = false;
+            info!("Left the shared canvas");
+        }
+    }
+
+    // The sender has already drawn it on their own copy
+    pub fn stroke(&self, client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, payload: &[u8]) -> Result<(), ProtocolError> {
+        let line = decode_stroke(payload)?;
+        let mut data = self.data.lock().unwrap();
+        let mut clients = clients.lock().unwrap();
+        match clients.get_mut(&client_id) {
+            Some(client) if client.on_board => {
+                if !within_limit(client, Instant::now()) {
+                    debug!("Dropped a stroke over the limit");
+                    return Ok(());
+                }
+            },
+            _ => return Err(ProtocolError::UnexpectedMessage)
+        }
+        draw_stroke(&mut data, &line);
+        forward(&mut clients, Some(client_id), &stroke(&line));
+        Ok(())
+    }
+
+    // The client asking has already cleared their own copy
+    pub fn clear(&self, client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>) -> Result<(), ProtocolError> {
+        let mut data = self.data.lock().unwrap();
+        let mut clients = clients.lock().unwrap();
+        if !on_board(&clients, client_id) {
+            return Err(ProtocolError::UnexpectedMessage);
+        }
+        data.fill(BACKGROUND);
+        forward(&mut clients, Some(client_id), &InfoData::new(InfoMsg::ClearBoard, 0).to_bytes());
+        debug!("Cleared the shared canvas");
+        Ok(())
+    }
+
+    // Hands over the board for posting as name, which the client has to be drawing
+    // under, and starts everyone on it afresh, the poster too. It's one lock
+    // throughout, so every stroke ends up either on what's posted or on the new board.
+    pub fn take(&self, client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, name: [u8; NAME_SIZE]) -> Result<Vec<u8>, ProtocolError> {
+        let mut data = self.data.lock().unwrap();
+        let mut clients = clients.lock().unwrap();
+        match clients.get_mut(&client_id) {
+            Some(client) if client.on_board => claim(client, name)?,
+            _ => return Err(ProtocolError::UnexpectedMessage)
+        }
+        let taken = std::mem::replace(&mut *data, vec![BACKGROUND; CANVAS_SIZE]);
+        forward(&mut clients, None, &InfoData::new(InfoMsg::ClearBoard, 0).to_bytes());
+        debug!("Took the shared canvas for posting");
+        Ok(taken)
+    }
+}
+
+fn within_limit(client: &mut Client, now: Instant) -> bool {
+    match &mut client.strokes {
+        Some((began, count)) if now.duration_since(*began) < STROKE_WINDOW => {
+            *count += 1;
+            *count <= MAX_STROKES
+        },
+        strokes => {
+            *strokes = Some((now, 1));
+            true
+        }
+    }
+}
+
+fn on_board(clients: &HashMap<Uuid, Client>, client_id: Uuid) -> bool {
+    clients.get(&client_id).is_some_and(|client| client.on_board)
+}
+
+// To everyone on the board but the client it came from, if any
+fn forward(clients: &mut HashMap<Uuid, Client>, from: Option<Uuid>, packet: &[u8]) {
+    metrics::count(&METRICS.messages_broadcast, 1);
+    for (id, client) in clients.iter_mut() {
+        if Some(*id) != from && client.on_board {
+            client.send(packet);
+        }
+    }
+}
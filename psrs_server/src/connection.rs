@@ -0,0 +1,122 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::Duration;
+use tungstenite::protocol::{Role, WebSocketConfig};
+use tungstenite::{Message, WebSocket};
+use psrs_protocol::*;
+
+// Nothing a client legitimately sends comes close to this in one WebSocket message
+const MAX_WEBSOCKET_MESSAGE: usize = 4 * (INFO_SIZE + PACKET_SIZE);
+
+// The sending half of a client connection, kept in the clients map. Over WebSocket
+// every write is one binary message; the byte stream inside is the same protocol
+// desktop clients speak over plain TCP.
+pub enum Connection {
+    Tcp(TcpStream),
+    WebSocket(Box<WebSocket<TcpStream>>)
+}
+
+// The receiving half, owned by the client's handler thread
+pub enum Inbound {
+    Tcp(TcpStream),
+    WebSocket {
+        socket: Box<WebSocket<TcpStream>>,
+        pending: Vec<u8>,
+        position: usize
+    }
+}
+
+fn websocket_config() -> WebSocketConfig {
+    WebSocketConfig {
+        write_buffer_size: 0,
+        max_message_size: Some(MAX_WEBSOCKET_MESSAGE),
+        max_frame_size: Some(MAX_WEBSOCKET_MESSAGE),
+        ..Default::default()
+    }
+}
+
+fn io_error(e: tungstenite::Error) -> std::io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => std::io::ErrorKind::UnexpectedEof.into(),
+        e => std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+pub fn tcp(stream: TcpStream) -> std::io::Result<(Connection, Inbound)> {
+    let inbound = stream.try_clone()?;
+    Ok((Connection::Tcp(stream), Inbound::Tcp(inbound)))
+}
+
+// Runs the HTTP upgrade, then splits the socket the same way as for TCP. The two
+// halves each get their own WebSocket state over clones of the one socket.
+pub fn websocket(stream: TcpStream, timeout: Duration) -> std::io::Result<(Connection, Inbound)> {
+    stream.set_read_timeout(Some(timeout))?;
+    let socket = tungstenite::accept_with_config(stream, Some(websocket_config()))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    socket.get_ref().set_read_timeout(None)?;
+    let writer = WebSocket::from_raw_socket(socket.get_ref().try_clone()?, Role::Server, Some(websocket_config()));
+    Ok((
+        Connection::WebSocket(Box::new(writer)),
+        Inbound::WebSocket {
+            socket: Box::new(socket),
+            pending: Vec::new(),
+            position: 0
+        }
+    ))
+}
+
+impl Connection {
+    pub fn send(&mut self, packet: &[u8]) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.write_all(packet),
+            Connection::WebSocket(socket) => socket.send(Message::Binary(packet.to_vec())).map_err(io_error)
+        }
+    }
+
+    pub fn shutdown(&self) {
+        let _ = match self {
+            Connection::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Connection::WebSocket(socket) => socket.get_ref().shutdown(Shutdown::Both)
+        };
+    }
+}
+
+impl Inbound {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Inbound::Tcp(stream) => stream.set_read_timeout(timeout),
+            Inbound::WebSocket { socket, .. } => socket.get_ref().set_read_timeout(timeout)
+        }
+    }
+}
+
+// Binary messages are read back to back as one byte stream, so a browser may
+// split or batch protocol messages across WebSocket messages however it likes.
+// Text messages are ignored. Pings are answered by tungstenite from this half;
+// browsers don't send them, so in practice only broadcasts write to the socket.
+impl Read for Inbound {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Inbound::Tcp(stream) => stream.read(buf),
+            Inbound::WebSocket { socket, pending, position } => {
+                while *position == pending.len() {
+                    match socket.read() {
+                        Ok(Message::Binary(data)) => {
+                            *pending = data;
+                            *position = 0;
+                        },
+                        Ok(Message::Close(_)) => return Ok(0),
+                        Ok(_) => {},
+                        Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => return Ok(0),
+                        Err(e) => return Err(io_error(e))
+                    }
+                }
+                let count = buf.len().min(pending.len() - *position);
+                buf[..count].copy_from_slice(&pending[*position..*position + count]);
+                *position += count;
+                Ok(count)
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use psrs_protocol::discovery::*;
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use crate::{Client, History};
+
+// Answers LAN discovery queries with our name, port and how busy we are
+pub fn spawn_responder(addr: &str, name: String, port: u16, clients: Arc<Mutex<HashMap<Uuid, Client>>>, history: Arc<Mutex<History>>) {
+    let socket = match UdpSocket::bind(addr) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!(%addr, error = %e, "Failed to start discovery responder");
+            return;
+        }
+    };
+    info!(%addr, "Answering discovery queries");
+    let id = Uuid::new_v4().as_u64_pair().0;
+    thread::spawn(move || {
+        let mut buffer = [0; 64];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!(error = %e, "Discovery receive failed");
+                    continue;
+                }
+            };
+            if !is_query(&buffer[..len]) {
+                continue;
+            }
+            let messages = history.lock().unwrap().history.len() as u32;
+            let online = clients.lock().unwrap().values().filter(|client| client.peer.is_none()).count() as u32;
+            let info = ServerInfo { id, name: name.clone(), port, clients: online, messages };
+            if let Err(e) = socket.send_to(&reply(&info), from) {
+                debug!(%from, error = %e, "Discovery reply failed");
+            }
+        }
+    });
+}
@@ -0,0 +1,68 @@
+use std::fs;
+use std::path::Path;
+use psrs_protocol::canvas::canvas_png;
+use psrs_protocol::*;
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+// Milliseconds since the epoch as "YYYY-MM-DD HH:MM:SS UTC"
+pub fn format_timestamp(millis: u128) -> String {
+    let secs = (millis / 1000) as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Days to civil date, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+// Writes one PNG per message plus an index.html listing them oldest first
+pub fn export(history: &[TextureData], dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut entries = String::new();
+    for item in history {
+        let file_name = format!("{}.png", item.id);
+        fs::write(dir.join(&file_name), canvas_png(&item.data))?;
+        entries.push_str(&format!(
+            "<figure><img src=\"{}\" width=\"200\" height=\"200\" alt=\"Drawing by {name}\"><figcaption><b>{name}</b><br><time>{}</time></figcaption></figure>\n",
+            file_name,
+            format_timestamp(item.timestamp),
+            name = escape_html(&display_name(&item.name))
+        ));
+    }
+
+    let index = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>PictoSend RS history</title>\n<style>\n{}</style>\n</head>\n<body>\n<h1>PictoSend RS history</h1>\n<p>{} messages</p>\n{}</body>\n</html>\n",
+        GALLERY_CSS,
+        history.len(),
+        entries
+    );
+    fs::write(dir.join("index.html"), index)
+}
+
+pub const GALLERY_CSS: &str = "body { font-family: sans-serif; background: #ddd; }
+figure { display: inline-block; margin: 8px; padding: 8px; background: #fff; }
+img { image-rendering: pixelated; border: 1px solid #999; }
+figcaption { font-size: 14px; }
+";
@@ -0,0 +1,44 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+// Just enough HTTP/1.1 for the metrics endpoint and web viewer: one GET per connection
+const MAX_HEADER_BYTES: u64 = 8 * 1024;
+
+pub struct Request {
+    pub method: String,
+    pub path: String
+}
+
+// Reads the request line and skips the headers, so closing the socket afterwards
+// doesn't reset the connection before the client has read the response
+pub fn read_request(stream: &TcpStream) -> std::io::Result<Request> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut reader = BufReader::new(stream).take(MAX_HEADER_BYTES);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && !line.trim_end().is_empty() {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    Ok(Request {
+        method: parts.next().unwrap_or("").to_string(),
+        path: parts.next().unwrap_or("").to_string()
+    })
+}
+
+pub fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> std::io::Result<()> {
+    write!(stream, "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n", body.len())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+pub fn not_found(stream: &mut TcpStream) -> std::io::Result<()> {
+    respond(stream, "404 Not Found", "text/plain", b"not found\n")
+}
+
+pub fn method_not_allowed(stream: &mut TcpStream) -> std::io::Result<()> {
+    respond(stream, "405 Method Not Allowed", "text/plain", b"method not allowed\n")
+}
@@ -0,0 +1,1006 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use std::fs::OpenOptions;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use psrs_protocol::*;
+use psrs_protocol::discovery::DISCOVERY_PORT;
+use tracing::{debug, error, info, info_span, warn};
+use plugin::{Plugin, Plugins};
+use relay::Relay;
+use board::Board;
+
+pub mod gallery;
+pub mod plugin;
+
+mod admin;
+mod board;
+mod connection;
+mod discovery;
+mod http;
+mod metrics;
+mod relay;
+mod web;
+mod websocket;
+
+use connection::{Connection, Inbound};
+use metrics::METRICS;
+
+const MAX_HISTORY: usize = 56;
+pub const DEFAULT_HISTORY_PATH: &str = "history";
+pub const DEFAULT_ADDR: &str = "0.0.0.0:6969";
+pub const DEFAULT_SERVER_NAME: &str = "PictoSend RS";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// Activity from one client is passed on at most this often
+const ACTIVITY_INTERVAL: Duration = Duration::from_secs(1);
+
+const HISTORY_MAGIC: &[u8; 8] = b"PSRSHIST";
+const HISTORY_VERSION: u32 = 4;
+// Anyone reacting to a message after this many have is ignored
+const MAX_REACTIONS: usize = 64;
+const MAX_HISTORY_FILE_BYTES: usize = HISTORY_MAGIC.len() + 4 + 8 + MAX_HISTORY * PACKET_SIZE
+    + 8 + MAX_HISTORY * (4 + 8 + MAX_REACTIONS * (4 + NAME_SIZE))
+    + 8 + MAX_HISTORY * (4 + KEY_SIZE);
+
+// Layout of history files written before messages had ids
+#[derive(Deserialize)]
+struct LegacyTextureData {
+    name: [u8; 24],
+    data: Vec<u8>,
+    request_history: bool,
+    request_history_length: bool,
+    history_length: i32,
+    confirm_history: bool,
+    timestamp: u128
+}
+
+// Layout of version 1 history files, written before replies
+#[derive(Deserialize)]
+struct TextureDataV1 {
+    name: [u8; 24],
+    data: Vec<u8>,
+    request_history: bool,
+    request_history_length: bool,
+    history_length: i32,
+    confirm_history: bool,
+    timestamp: u128,
+    id: i32
+}
+
+struct Client {
+    stream: Connection,
+    addr: SocketAddr,
+    has_history: bool,
+    errorstrikes: i8,
+    // Fixed by Hello, or by the first drawing for clients that don't send one
+    name: Option<[u8; 24]>,
+    // What the connection's drawings are owned by: the key from Hello, or one
+    // made up for the connection, whose drawings nobody can delete once it's gone
+    key: [u8; KEY_SIZE],
+    write_failures: u64,
+    // The id of the server at the other end if this is a relay link
+    peer: Option<u64>,
+    last_activity: Option<Instant>,
+    // Gets the shared canvas's strokes
+    on_board: bool,
+    // When the current STROKE_WINDOW began and how many strokes it has had
+    strokes: Option<(Instant, u32)>
+}
+
+impl Client {
+    fn try_send(&mut self, packet: &[u8]) -> std::io::Result<()> {
+        match self.stream.send(packet) {
+            Ok(()) => {
+                metrics::count(&METRICS.bytes_out, packet.len());
+                Ok(())
+            },
+            Err(e) => {
+                debug!(addr = %self.addr, error = %e, "Write to client failed");
+                self.write_failures += 1;
+                metrics::count(&METRICS.write_failures, 1);
+                Err(e)
+            }
+        }
+    }
+
+    // Write errors aren't fatal here; the handler thread notices the broken connection on its next read
+    fn send(&mut self, packet: &[u8]) {
+        let _ = self.try_send(packet);
+    }
+
+    fn display_name(&self) -> String {
+        match self.name {
+            Some(name) => validate_name(&name).unwrap_or_else(|_| String::from("?")),
+            None => String::from("-")
+        }
+    }
+}
+
+// Who stamped which reaction on a message
+type Reactions = Vec<(Reaction, [u8; NAME_SIZE])>;
+// The key each message can be deleted with
+type Owners = BTreeMap<i32, [u8; KEY_SIZE]>;
+
+struct History {
+    history: Vec<TextureData>,
+    // Kept beside the drawings rather than in them, so drawings stay PACKET_SIZE on the wire
+    reactions: BTreeMap<i32, Reactions>,
+    // Relayed messages and those from before keys have no owner, so they stay
+    owners: Owners,
+    next_id: i32,
+    // Bumped on every change so watchers (the web viewer) can tell something happened
+    revision: u64,
+    // Where changes are saved; None keeps the history in memory only
+    path: Option<PathBuf>
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            history: Vec::new(),
+            reactions: BTreeMap::new(),
+            owners: BTreeMap::new(),
+            next_id: 1,
+            revision: 0,
+            path: None
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<History, String> {
+        let mut history = History::new();
+        let size = std::fs::metadata(path).map_err(|e| e.to_string())?.len();
+        if size > MAX_HISTORY_FILE_BYTES as u64 {
+            return Err(format!("history file is {size} bytes, expected at most {MAX_HISTORY_FILE_BYTES}"));
+        }
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+
+        if bytes.starts_with(HISTORY_MAGIC) {
+            let versioned = &bytes[HISTORY_MAGIC.len()..];
+            let version: u32 = decode(versioned, 4).map_err(|e| e.to_string())?;
+            history.history = match version {
+                1 => {
+                    let (_, entries): (u32, Vec<TextureDataV1>) = decode(versioned, MAX_HISTORY_FILE_BYTES).map_err(|e| e.to_string())?;
+                    entries.into_iter().map(|item| TextureData {
+                        name: item.name,
+                        data: item.data,
+                        request_history: item.request_history,
+                        request_history_length: item.request_history_length,
+                        history_length: item.history_length,
+                        confirm_history: item.confirm_history,
+                        timestamp: item.timestamp,
+                        id: item.id,
+                        reply_to: 0
+                    }).collect()
+                },
+                2 => {
+                    let (_, entries): (u32, Vec<TextureData>) = decode(versioned, MAX_HISTORY_FILE_BYTES).map_err(|e| e.to_string())?;
+                    entries
+                },
+                3 => {
+                    let (_, entries, reactions): (u32, Vec<TextureData>, BTreeMap<i32, Reactions>) = decode(versioned, MAX_HISTORY_FILE_BYTES).map_err(|e| e.to_string())?;
+                    history.reactions = reactions;
+                    entries
+                },
+                HISTORY_VERSION => {
+                    let (_, entries, reactions, owners): (u32, Vec<TextureData>, BTreeMap<i32, Reactions>, Owners) = decode(versioned, MAX_HISTORY_FILE_BYTES).map_err(|e| e.to_string())?;
+                    history.reactions = reactions;
+                    history.owners = owners;
+                    entries
+                },
+                _ => return Err(format!("history file is version {version}, expected at most {HISTORY_VERSION}"))
+            };
+            debug!(version, "Loaded versioned history");
+        } else {
+            let legacy: Vec<LegacyTextureData> = decode(&bytes, MAX_HISTORY_FILE_BYTES).map_err(|e| e.to_string())?;
+            info!("Loaded legacy history, assigning message ids");
+            history.history = legacy.into_iter().enumerate().map(|(index, item)| TextureData {
+                name: item.name,
+                data: item.data,
+                request_history: item.request_history,
+                request_history_length: item.request_history_length,
+                history_length: item.history_length,
+                confirm_history: item.confirm_history,
+                timestamp: item.timestamp,
+                id: index as i32 + 1,
+                reply_to: 0
+            }).collect();
+        }
+
+        history.next_id = history.history.iter().map(|item| item.id).max().unwrap_or(0) + 1;
+        Ok(history)
+    }
+
+    // Serialize and save (overwrite) to file. The new history is written next to the
+    // old one and renamed over it, so an interrupted save never leaves a truncated file.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(HISTORY_MAGIC)?;
+        bincode::serialize_into(&mut writer, &(HISTORY_VERSION, &self.history, &self.reactions, &self.owners))
+            .map_err(std::io::Error::other)?;
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    pub fn persist(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = self.save(path) {
+                error!(path = %path.display(), error = %e, "Failed to save history");
+            }
+        }
+    }
+
+    // Stamps the reaction, or takes it back if name already had, and returns the
+    // message's new totals. None if there's no such message.
+    pub fn react(&mut self, stamp: &React, name: [u8; NAME_SIZE]) -> Option<ReactionCounts> {
+        if !self.history.iter().any(|item| item.id == stamp.message_id) {
+            return None;
+        }
+        let reactions = self.reactions.entry(stamp.message_id).or_default();
+        let entry = (stamp.reaction, name);
+        match reactions.iter().position(|reacted| *reacted == entry) {
+            Some(index) => {
+                reactions.remove(index);
+            },
+            None if reactions.len() < MAX_REACTIONS => reactions.push(entry),
+            None => debug!(message_id = stamp.message_id, "Message has all the reactions it can take")
+        }
+        if reactions.is_empty() {
+            self.reactions.remove(&stamp.message_id);
+        }
+        Some(self.reaction_counts(stamp.message_id))
+    }
+
+    pub fn reaction_counts(&self, message_id: i32) -> ReactionCounts {
+        let mut counts = ReactionCounts { message_id, counts: [0; Reaction::ALL.len()] };
+        for (reaction, _) in self.reactions.get(&message_id).into_iter().flatten() {
+            if let Some(index) = Reaction::ALL.iter().position(|r| r == reaction) {
+                counts.counts[index] += 1;
+            }
+        }
+        counts
+    }
+
+    // Once a message is deleted or pushed out of the history its reactions and owner go too
+    fn forget_removed(&mut self) {
+        let history = &self.history;
+        self.reactions.retain(|message_id, _| history.iter().any(|item| item.id == *message_id));
+        self.owners.retain(|message_id, _| history.iter().any(|item| item.id == *message_id));
+    }
+}
+
+fn broadcast(clients: &mut HashMap<Uuid, Client>, packet: &[u8]) {
+    metrics::count(&METRICS.messages_broadcast, 1);
+    for client in clients.values_mut() {
+        if client.has_history {
+            client.send(packet);
+        }
+    }
+}
+
+enum ReadError {
+    Io(std::io::Error),
+    Protocol(ProtocolError)
+}
+
+impl From<std::io::Error> for ReadError {
+    fn from(e: std::io::Error) -> ReadError {
+        ReadError::Io(e)
+    }
+}
+
+fn read_message(stream: &mut impl Read) -> Result<(InfoData, Vec<u8>), ReadError> {
+    let mut header = [0; INFO_SIZE];
+    stream.read_exact(&mut header)?;
+    let info_data = decode_header(&header).map_err(ReadError::Protocol)?;
+    let payload_len = inbound_payload_len(&info_data).map_err(ReadError::Protocol)?;
+    let mut payload = vec![0; payload_len];
+    stream.read_exact(&mut payload)?;
+    metrics::count(&METRICS.bytes_in, INFO_SIZE + payload_len);
+    Ok((info_data, payload))
+}
+
+fn expect_message(stream: &mut impl Read, expected: InfoMsg) -> Result<(), ReadError> {
+    let (info_data, _) = read_message(stream)?;
+    if info_data.msg != expected {
+        return Err(ReadError::Protocol(ProtocolError::UnexpectedMessage));
+    }
+    Ok(())
+}
+
+// Relay peers only log errors, never answer them, so we don't send them any
+// either: two servers would otherwise trade errors for as long as they're linked.
+fn send_error(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, error: ProtocolError) {
+    METRICS.protocol_error(error);
+    let mut clients = clients.lock().unwrap();
+    if let Some(client) = clients.get_mut(&client_id).filter(|client| client.peer.is_none()) {
+        client.send(&error.reply().to_bytes());
+    }
+}
+
+// Stands in for the Error a client would otherwise get, so it knows the drawing
+// isn't coming back. None is a plugin's doing; it has told the author why.
+fn refuse_drawing(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, error: Option<ProtocolError>) {
+    let code = match error {
+        Some(error) => {
+            METRICS.protocol_error(error);
+            error as i32
+        },
+        None => 0
+    };
+    let mut clients = clients.lock().unwrap();
+    if let Some(client) = clients.get_mut(&client_id) {
+        client.send(&InfoData::new(InfoMsg::DrawingRefused, code).to_bytes());
+    }
+}
+
+// Only once, before anything that goes by the name. It's taken as given: names
+// aren't owned, keys are.
+fn say_hello(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, greeting: &Hello) -> Result<(), ProtocolError> {
+    let mut clients = clients.lock().unwrap();
+    match clients.get_mut(&client_id) {
+        Some(client) if client.name.is_some() => Err(ProtocolError::UnexpectedMessage),
+        Some(client) => {
+            client.name = Some(greeting.name);
+            client.key = greeting.key;
+            Ok(())
+        },
+        None => Ok(())
+    }
+}
+
+// A connection draws under one name only, so it can't pass as someone else once it's drawn
+fn claim_name(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, name: [u8; NAME_SIZE]) -> Result<(), ProtocolError> {
+    match clients.lock().unwrap().get_mut(&client_id) {
+        Some(client) => claim(client, name),
+        None => Ok(())
+    }
+}
+
+fn claim(client: &mut Client, name: [u8; NAME_SIZE]) -> Result<(), ProtocolError> {
+    if client.name.is_some_and(|known| known != name) {
+        return Err(ProtocolError::BadName);
+    }
+    client.name = Some(name);
+    Ok(())
+}
+
+fn key_of(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>) -> Option<[u8; KEY_SIZE]> {
+    clients.lock().unwrap().get(&client_id).map(|client| client.key)
+}
+
+fn send_to(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, packet: &[u8]) -> std::io::Result<()> {
+    match clients.lock().unwrap().get_mut(&client_id) {
+        Some(client) => client.try_send(packet),
+        None => Err(std::io::ErrorKind::NotConnected.into())
+    }
+}
+
+// Sends the history as it is now, without holding the lock while we wait on the
+// client, so a slow one can't hold up everyone else. Whatever changed in the
+// meantime follows once it has confirmed, before anything is broadcast to it.
+fn send_history(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, history: &Arc<Mutex<History>>, stream: &mut Inbound) -> Result<(), ReadError> {
+    let (history_data, sent_ids, next_id) = {
+        let history_locked = history.lock().unwrap();
+        let sent_ids: Vec<i32> = history_locked.history.iter().map(|item| item.id).collect();
+        (bincode::serialize(&(history_locked.history)).unwrap(), sent_ids, history_locked.next_id)
+    };
+
+    send_to(client_id, clients, &InfoData::new(InfoMsg::HistoryLength, history_data.len() as i32).to_bytes())?;
+    debug!(bytes = history_data.len(), "Sent history length, now expecting history request");
+
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let result = expect_message(stream, InfoMsg::RequestHistory)
+        .and_then(|_| {
+            debug!("It's a history request, sending history");
+            send_to(client_id, clients, &history_data)?;
+            expect_message(stream, InfoMsg::ConfirmReceivedHistory)
+        });
+    stream.set_read_timeout(None)?;
+    result?;
+
+    debug!("Client confirmed history");
+    let history_locked = history.lock().unwrap();
+    let mut clients = clients.lock().unwrap();
+    if let Some(client) = clients.get_mut(&client_id) {
+        // Drawings stored since, then those deleted or pushed out of the history
+        for item in history_locked.history.iter().filter(|item| item.id >= next_id) {
+            client.send(&drawing_message(item));
+        }
+        for message_id in sent_ids.iter().filter(|id| !history_locked.history.iter().any(|item| item.id == **id)) {
+            client.send(&InfoData::new(InfoMsg::MessageDeleted, *message_id).to_bytes());
+        }
+        // Then the reactions so far, before anything new can change them
+        for message_id in history_locked.reactions.keys() {
+            client.send(&reaction_counts(&history_locked.reaction_counts(*message_id)));
+        }
+        client.has_history = true;
+    }
+    Ok(())
+}
+
+// Goes by key rather than name, so drawing under someone's name doesn't let you
+// delete their messages
+fn delete_message(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, history: &Arc<Mutex<History>>, message_id: i32) {
+    debug!(message_id, "Got delete request");
+    let requester = clients.lock().unwrap().get(&client_id).map(|client| client.key);
+    let mut history_locked = history.lock().unwrap();
+    let position = history_locked.history.iter().position(|item| item.id == message_id);
+
+    match position {
+        Some(index) if requester.is_some() && history_locked.owners.get(&message_id) == requester.as_ref() => {
+            history_locked.history.remove(index);
+            history_locked.forget_removed();
+            history_locked.revision += 1;
+            history_locked.persist();
+            info!(message_id, history_len = history_locked.history.len(), "Deleted message");
+
+            let response = InfoData::new(InfoMsg::MessageDeleted, message_id);
+            let mut clients = clients.lock().unwrap();
+            broadcast(&mut clients, &response.to_bytes());
+        }
+        _ => {
+            warn!(message_id, "Refused to delete message");
+            let response = InfoData::new(InfoMsg::DeleteRefused, message_id);
+            let mut clients = clients.lock().unwrap();
+            if let Some(client) = clients.get_mut(&client_id) {
+                client.send(&response.to_bytes());
+            }
+        }
+    }
+}
+
+// Reactions stay on this server, like deletions, since ids mean nothing anywhere
+// else. They go by the name the client said hello with, or first drew as if it
+// didn't, so one we can't name yet can't react.
+fn react_to(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, history: &Arc<Mutex<History>>, stamp: &React) -> Result<(), ProtocolError> {
+    let name = clients.lock().unwrap().get(&client_id).and_then(|client| client.name).ok_or(ProtocolError::UnexpectedMessage)?;
+    let mut history_locked = history.lock().unwrap();
+    let Some(counts) = history_locked.react(stamp, name) else {
+        debug!(message_id = stamp.message_id, "Reaction to a message we don't have");
+        return Ok(());
+    };
+    history_locked.persist();
+    debug!(message_id = stamp.message_id, reaction = ?stamp.reaction, "Reacted to message");
+    let mut clients = clients.lock().unwrap();
+    broadcast(&mut clients, &reaction_counts(&counts));
+    Ok(())
+}
+
+// Passes "name is drawing" on to everyone else, under the name the client said
+// hello with (or, without one, first drew as); until then we don't know who it
+// is, so there's nothing to pass on. It isn't stored or relayed, and extra ones within ACTIVITY_INTERVAL are
+// dropped so a busy pen can't flood anyone.
+fn share_activity(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>) {
+    let now = Instant::now();
+    let mut clients = clients.lock().unwrap();
+    let name = match clients.get_mut(&client_id) {
+        Some(Client { name: Some(name), last_activity, .. }) if last_activity.is_none_or(|last| now.duration_since(last) >= ACTIVITY_INTERVAL) => {
+            *last_activity = Some(now);
+            *name
+        },
+        _ => return
+    };
+    metrics::count(&METRICS.messages_broadcast, 1);
+    let packet = activity(&name);
+    for (id, client) in clients.iter_mut() {
+        if *id != client_id && client.has_history && client.peer.is_none() {
+            client.send(&packet);
+        }
+    }
+}
+
+fn drawing_message(texture_data: &TextureData) -> Vec<u8> {
+    let mut packet = InfoData::new(InfoMsg::Drawing, PACKET_SIZE as i32).to_bytes();
+    packet.extend(bincode::serialize(texture_data).unwrap());
+    packet
+}
+
+// Returns the drawing as stored, with its id. Only the owner's key can delete it.
+fn add_drawing(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, history: &Arc<Mutex<History>>, mut texture_data: TextureData, owner: Option<[u8; KEY_SIZE]>) -> TextureData {
+    // Add the message to history
+    let mut history_locked = history.lock().unwrap();
+    // Ids we haven't handed out yet can't be what it's answering
+    if texture_data.reply_to >= history_locked.next_id {
+        texture_data.reply_to = 0;
+    }
+    texture_data.id = history_locked.next_id;
+    history_locked.next_id += 1;
+    let packet = drawing_message(&texture_data);
+
+    history_locked.history.push(texture_data.clone());
+    if let Some(owner) = owner {
+        history_locked.owners.insert(texture_data.id, owner);
+    }
+    if history_locked.history.len() > MAX_HISTORY {
+        history_locked.history.remove(0);
+        history_locked.forget_removed();
+    }
+    history_locked.history.sort_by_key(|item| item.timestamp);
+    history_locked.revision += 1;
+    debug!(history_len = history_locked.history.len(), "Stored drawing");
+    history_locked.persist();
+
+    // Send updated texture data to all clients
+    let mut clients = clients.lock().unwrap();
+    broadcast(&mut clients, &packet);
+    texture_data
+}
+
+// Adds a freshly accepted connection to the clients map
+fn register(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, stream: Connection, addr: SocketAddr) -> Uuid {
+    let mut locked_clients = clients.lock().unwrap();
+    let client_id = Uuid::new_v4();
+    locked_clients.insert(
+        client_id,
+        Client {
+            stream,
+            addr,
+            has_history: false,
+            errorstrikes: 0,
+            name: None,
+            key: *Uuid::new_v4().as_bytes(),
+            write_failures: 0,
+            peer: None,
+            last_activity: None,
+            on_board: false,
+            strokes: None
+        },
+    );
+    metrics::count(&METRICS.connections, 1);
+    info!(id = %client_id, %addr, clients = locked_clients.len(), "New connection");
+    client_id
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_client(client_id: Uuid, mut stream: Inbound, addr: SocketAddr, clients: Arc<Mutex<HashMap<Uuid, Client>>>, history: Arc<Mutex<History>>, plugins: Arc<Plugins>, relay: Arc<Relay>, board: Arc<Board>) {
+    // Everything logged from this thread carries the client's id, address and (once known) name
+    let span = info_span!("client", id = %client_id, %addr, name = tracing::field::Empty);
+    let _entered = span.enter();
+    loop {
+        let mut should_break = false;
+
+        match read_message(&mut stream) {
+            Ok((info_data, payload)) => {
+                metrics::count(&METRICS.messages_received, 1);
+                match info_data.msg {
+                    InfoMsg::RequestHistoryLength => {
+                        debug!("Got history length request");
+                        match send_history(client_id, &clients, &history, &mut stream) {
+                            Ok(()) => plugins.client_joined(client_id, &clients),
+                            Err(ReadError::Protocol(e)) => {
+                                warn!(error = ?e, "History exchange failed");
+                                send_error(client_id, &clients, e);
+                                should_break = e.is_fatal();
+                            },
+                            Err(ReadError::Io(e)) => {
+                                warn!(error = %e, "History exchange failed");
+                                should_break = true;
+                            }
+                        }
+                    },
+                    InfoMsg::Hello => {
+                        match decode_hello(&payload).and_then(|greeting| say_hello(client_id, &clients, &greeting).map(|()| greeting)) {
+                            Ok(greeting) => {
+                                span.record("name", validate_name(&greeting.name).unwrap_or_default());
+                                debug!("Client said hello");
+                            },
+                            Err(e) => {
+                                warn!(error = ?e, "Rejected hello");
+                                send_error(client_id, &clients, e);
+                            }
+                        }
+                    },
+                    InfoMsg::DeleteMessage => {
+                        delete_message(client_id, &clients, &history, info_data.number);
+                    },
+                    InfoMsg::Drawing => {
+                        let claimed = decode_drawing(&payload, now_millis())
+                            .and_then(|texture_data| claim_name(client_id, &clients, texture_data.name).map(|()| texture_data));
+                        match claimed {
+                            Ok(texture_data) => {
+                                span.record("name", validate_name(&texture_data.name).unwrap_or_default());
+                                info!("Got drawing from client");
+                                match plugins.drawing_received(client_id, &clients, texture_data) {
+                                    Some(texture_data) => {
+                                        let stored = add_drawing(&clients, &history, texture_data, key_of(client_id, &clients));
+                                        relay.drawing_added(&clients, &stored);
+                                    },
+                                    None => refuse_drawing(client_id, &clients, None)
+                                }
+                            },
+                            Err(e) => {
+                                warn!(error = ?e, "Rejected drawing");
+                                refuse_drawing(client_id, &clients, Some(e));
+                            }
+                        }
+                    },
+                    InfoMsg::Activity => {
+                        match decode_activity(&payload) {
+                            Ok(_) => share_activity(client_id, &clients),
+                            Err(e) => {
+                                warn!(error = ?e, "Rejected activity");
+                                send_error(client_id, &clients, e);
+                            }
+                        }
+                    },
+                    InfoMsg::React => {
+                        match decode_react(&payload).and_then(|stamp| react_to(client_id, &clients, &history, &stamp)) {
+                            Ok(()) => {},
+                            Err(e) => {
+                                warn!(error = ?e, "Rejected reaction");
+                                send_error(client_id, &clients, e);
+                            }
+                        }
+                    },
+                    InfoMsg::JoinBoard => board.join(client_id, &clients),
+                    InfoMsg::LeaveBoard => board.leave(client_id, &clients),
+                    InfoMsg::Stroke => {
+                        if let Err(e) = board.stroke(client_id, &clients, &payload) {
+                            warn!(error = ?e, "Rejected stroke");
+                            send_error(client_id, &clients, e);
+                        }
+                    },
+                    InfoMsg::ClearBoard => {
+                        if let Err(e) = board.clear(client_id, &clients) {
+                            warn!(error = ?e, "Refused to clear the shared canvas");
+                            send_error(client_id, &clients, e);
+                        }
+                    },
+                    // Goes through plugins like any other drawing, and is the poster's under the
+                    // name they draw as. The board starts afresh as it's taken, so no stroke
+                    // can be lost in between; one a plugin turns away is gone like any other.
+                    InfoMsg::PostBoard => {
+                        let posted = decode_post_board(&payload)
+                            .and_then(|name| Ok((name, board.take(client_id, &clients, name)?)));
+                        match posted {
+                            Ok((name, data)) => {
+                                info!("Posting the shared canvas");
+                                let texture_data = TextureData {
+                                    name,
+                                    data,
+                                    request_history: false,
+                                    request_history_length: false,
+                                    history_length: 0,
+                                    confirm_history: false,
+                                    timestamp: now_millis(),
+                                    id: 0,
+                                    reply_to: 0
+                                };
+                                if let Some(texture_data) = plugins.drawing_received(client_id, &clients, texture_data) {
+                                    let stored = add_drawing(&clients, &history, texture_data, key_of(client_id, &clients));
+                                    relay.drawing_added(&clients, &stored);
+                                }
+                            },
+                            Err(e) => {
+                                warn!(error = ?e, "Refused to post the shared canvas");
+                                send_error(client_id, &clients, e);
+                            }
+                        }
+                    },
+                    InfoMsg::RelayHello => {
+                        if let Err(e) = relay.peer_hello(client_id, &clients, &payload) {
+                            warn!(error = ?e, "Refused relay link");
+                            send_error(client_id, &clients, e);
+                            should_break = true;
+                        }
+                    },
+                    // Relayed drawings were checked by plugins on the server they were drawn on
+                    InfoMsg::RelayDrawing => {
+                        match relay.drawing_relayed(client_id, &clients, &payload) {
+                            Ok(Some(mut texture_data)) => {
+                                debug!("Got relayed drawing");
+                                // It names the drawing it answers by the other server's numbering
+                                texture_data.reply_to = 0;
+                                add_drawing(&clients, &history, texture_data, None);
+                            },
+                            Ok(None) => {},
+                            Err(e) => {
+                                warn!(error = ?e, "Rejected relayed drawing");
+                                send_error(client_id, &clients, e);
+                                should_break = e.is_fatal();
+                            }
+                        }
+                    },
+                    InfoMsg::Error => {
+                        let from_peer = clients.lock().unwrap().get(&client_id).is_some_and(|client| client.peer.is_some());
+                        if from_peer {
+                            warn!(code = info_data.number, "Relay peer rejected what we sent it");
+                        } else {
+                            send_error(client_id, &clients, ProtocolError::UnexpectedMessage);
+                        }
+                    },
+                    _ => {}
+                }
+            }
+            Err(ReadError::Protocol(e)) => {
+                warn!(error = ?e, "Bad message from client");
+                send_error(client_id, &clients, e);
+                should_break = e.is_fatal();
+            }
+            Err(ReadError::Io(e)) => {
+                if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                    info!("Client disconnected");
+                    should_break = true;
+                } else {
+                    warn!(error = %e, "Failed to receive from client");
+                    let mut clients = clients.lock().unwrap();
+                    match clients.get_mut(&client_id) {
+                        Some(client) => {
+                            client.errorstrikes += 1;
+                            should_break = client.errorstrikes > 4;
+                        },
+                        None => should_break = true
+                    }
+                }
+            }
+        }
+
+        if should_break {
+            plugins.client_left(client_id, &clients);
+            let mut locked_clients = clients.lock().unwrap();
+            locked_clients.remove(&client_id);
+            break;
+        }
+    }
+}
+
+// Tells everyone we're going away and writes out history for the last time. The
+// path is taken out of the history so no handler thread still running can save after us.
+fn shutdown(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, history: &Arc<Mutex<History>>) {
+    info!("Shutting down");
+    let mut history_locked = history.lock().unwrap();
+    let mut clients = clients.lock().unwrap();
+    let notice = InfoData::new(InfoMsg::ServerShutdown, 0).to_bytes();
+    for client in clients.values_mut() {
+        // Relay peers just see the link close and keep trying to reconnect
+        if client.peer.is_none() {
+            client.send(&notice);
+        }
+        client.stream.shutdown();
+    }
+    info!(clients = clients.len(), "Notified clients");
+    if let Some(path) = history_locked.path.take() {
+        match history_locked.save(&path) {
+            Ok(()) => info!("Saved history, bye"),
+            Err(e) => error!(error = %e, "Failed to save history")
+        }
+    }
+}
+
+// Reads a history file the way the server would, e.g. to export it
+pub fn load_history(path: &Path) -> Result<Vec<TextureData>, String> {
+    History::load(path).map(|history| history.history)
+}
+
+pub struct Config {
+    pub addr: String,
+    // Shown to clients looking for servers on the LAN
+    pub name: String,
+    pub history_path: Option<PathBuf>,
+    pub metrics_addr: Option<String>,
+    pub web_addr: Option<String>,
+    pub ws_addr: Option<String>,
+    pub discovery_addr: Option<String>,
+    pub plugins: Vec<Arc<dyn Plugin>>,
+    // Servers to mirror drawings with; both ends need the same relay_key
+    pub relay_peers: Vec<String>,
+    pub relay_key: Option<String>
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            addr: String::from(DEFAULT_ADDR),
+            name: String::from(DEFAULT_SERVER_NAME),
+            history_path: Some(PathBuf::from(DEFAULT_HISTORY_PATH)),
+            metrics_addr: Some(String::from(metrics::DEFAULT_METRICS_ADDR)),
+            web_addr: None,
+            ws_addr: None,
+            discovery_addr: Some(format!("0.0.0.0:{DISCOVERY_PORT}")),
+            plugins: Vec::new(),
+            relay_peers: Vec::new(),
+            relay_key: None
+        }
+    }
+}
+
+impl Config {
+    // PSRS_ADDR, PSRS_NAME, PSRS_METRICS_ADDR and PSRS_DISCOVERY_ADDR ("off" to
+    // disable either), PSRS_WEB_ADDR, PSRS_WS_ADDR, PSRS_PLUGINS (a comma separated
+    // list of built-in plugins) and PSRS_RELAY_PEERS (comma separated addresses)
+    // with PSRS_RELAY_KEY
+    pub fn from_env() -> Config {
+        let defaults = Config::default();
+        Config {
+            addr: std::env::var("PSRS_ADDR").unwrap_or(defaults.addr),
+            name: std::env::var("PSRS_NAME").unwrap_or(defaults.name),
+            metrics_addr: match std::env::var("PSRS_METRICS_ADDR") {
+                Ok(addr) if addr == "off" => None,
+                Ok(addr) => Some(addr),
+                Err(_) => defaults.metrics_addr
+            },
+            web_addr: std::env::var("PSRS_WEB_ADDR").ok(),
+            ws_addr: std::env::var("PSRS_WS_ADDR").ok(),
+            discovery_addr: match std::env::var("PSRS_DISCOVERY_ADDR") {
+                Ok(addr) if addr == "off" => None,
+                Ok(addr) => Some(addr),
+                Err(_) => defaults.discovery_addr
+            },
+            plugins: std::env::var("PSRS_PLUGINS").unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .filter_map(|name| {
+                    let plugin = plugin::builtin(name);
+                    if plugin.is_none() {
+                        warn!(plugin = name, "Unknown plugin");
+                    }
+                    plugin
+                })
+                .collect(),
+            relay_peers: std::env::var("PSRS_RELAY_PEERS").unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|addr| !addr.is_empty())
+                .map(String::from)
+                .collect(),
+            relay_key: std::env::var("PSRS_RELAY_KEY").ok(),
+            ..defaults
+        }
+    }
+}
+
+pub struct Server {
+    listener: TcpListener,
+    clients: Arc<Mutex<HashMap<Uuid, Client>>>,
+    history: Arc<Mutex<History>>,
+    plugins: Arc<Plugins>,
+    relay: Arc<Relay>,
+    board: Arc<Board>,
+    ws_addr: Option<SocketAddr>,
+    shutting_down: Arc<AtomicBool>
+}
+
+impl Server {
+    // Loads the history and starts listening, along with whichever extra endpoints are configured
+    pub fn bind(config: &Config) -> Result<Server, String> {
+        match &config.relay_key {
+            Some(key) if key.is_empty() || key.len() > MAX_RELAY_KEY_SIZE => {
+                return Err(format!("the relay key must be 1 to {MAX_RELAY_KEY_SIZE} bytes"));
+            },
+            None if !config.relay_peers.is_empty() => return Err(String::from("relay peers need a relay key")),
+            _ => {}
+        }
+        let listener = TcpListener::bind(&config.addr).map_err(|e| format!("can't listen on {}: {e}", config.addr))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        info!(addr = %listener.local_addr().map_err(|e| e.to_string())?, "Listening");
+
+        let mut history = match &config.history_path {
+            Some(path) if path.exists() => {
+                let loaded = History::load(path).map_err(|e| format!("can't load {}: {e}", path.display()))?;
+                info!(messages = loaded.history.len(), "Loaded data.");
+                loaded
+            },
+            Some(_) => {
+                info!("File does not exist, initializing new data.");
+                History::new()
+            },
+            None => History::new()
+        };
+        history.path = config.history_path.clone();
+
+        let clients = Arc::new(Mutex::new(HashMap::new()));
+        let history = Arc::new(Mutex::new(history));
+        let plugins = Arc::new(Plugins::new(config.plugins.clone()));
+        let relay = Arc::new(Relay::new(config.relay_key.clone()));
+        let board = Arc::new(Board::new());
+        for peer in &config.relay_peers {
+            relay.spawn_link(peer.clone(), Arc::clone(&clients), Arc::clone(&history), Arc::clone(&plugins), Arc::clone(&board));
+        }
+        if let Some(addr) = &config.metrics_addr {
+            metrics::spawn_endpoint(addr, Arc::clone(&clients), Arc::clone(&history));
+        }
+        if let Some(addr) = &config.web_addr {
+            web::spawn_viewer(addr, Arc::clone(&history));
+        }
+        let ws_addr = config.ws_addr.as_ref().and_then(|addr| {
+            websocket::spawn_gateway(addr, Arc::clone(&clients), Arc::clone(&history), Arc::clone(&plugins), Arc::clone(&relay), Arc::clone(&board))
+        });
+        if let Some(addr) = &config.discovery_addr {
+            let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+            discovery::spawn_responder(addr, config.name.clone(), port, Arc::clone(&clients), Arc::clone(&history));
+        }
+
+        Ok(Server {
+            listener,
+            clients,
+            history,
+            plugins,
+            relay,
+            board,
+            ws_addr,
+            shutting_down: Arc::new(AtomicBool::new(false))
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr().unwrap()
+    }
+
+    // Where the WebSocket gateway is listening, if it was asked for and could start
+    pub fn ws_addr(&self) -> Option<SocketAddr> {
+        self.ws_addr
+    }
+
+    // Setting this makes run() notify everyone, save and return
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.shutting_down)
+    }
+
+    pub fn spawn_admin_console(&self) {
+        admin::spawn_console(Arc::clone(&self.clients), Arc::clone(&self.history), Arc::clone(&self.shutting_down));
+    }
+
+    pub fn run(self) {
+        let Server { listener, clients, history, plugins, relay, board, shutting_down, .. } = self;
+
+        // Polled rather than blocking in accept so a signal can stop us between connections
+        while !shutting_down.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        warn!(error = %e, "Connection dropped before setup");
+                        continue;
+                    }
+                    let addr = match stream.peer_addr() {
+                        Ok(addr) => addr,
+                        Err(e) => {
+                            warn!(error = %e, "Connection dropped before setup");
+                            continue;
+                        }
+                    };
+                    let (connection, inbound) = match connection::tcp(stream) {
+                        Ok(halves) => halves,
+                        Err(e) => {
+                            error!(error = %e, "Failed to clone stream");
+                            continue;
+                        }
+                    };
+
+                    let client_id = register(&clients, connection, addr);
+                    let clients_ref_clone = Arc::clone(&clients);
+                    let history_ref_clone = Arc::clone(&history);
+                    let plugins = Arc::clone(&plugins);
+                    let relay = Arc::clone(&relay);
+                    let board = Arc::clone(&board);
+                    thread::spawn(move || {
+                        handle_client(client_id, inbound, addr, clients_ref_clone, history_ref_clone, plugins, relay, board);
+                    });
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => {
+                    warn!(error = %e, "Connection failed");
+                }
+            }
+        }
+
+        drop(listener);
+        shutdown(&clients, &history);
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+use psrs_protocol::*;
+
+use crate::{http, Client, History};
+
+pub const DEFAULT_METRICS_ADDR: &str = "127.0.0.1:6970";
+
+// Process-wide counters, bumped from the handler threads. Gauges (clients,
+// history size) are read from the shared state when scraped instead.
+pub struct Metrics {
+    pub connections: AtomicU64,
+    pub messages_received: AtomicU64,
+    pub messages_broadcast: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub write_failures: AtomicU64,
+    protocol_errors: [AtomicU64; ProtocolError::ALL.len() + 1]
+}
+
+pub static METRICS: Metrics = Metrics {
+    connections: AtomicU64::new(0),
+    messages_received: AtomicU64::new(0),
+    messages_broadcast: AtomicU64::new(0),
+    bytes_in: AtomicU64::new(0),
+    bytes_out: AtomicU64::new(0),
+    write_failures: AtomicU64::new(0),
+    protocol_errors: [const { AtomicU64::new(0) }; ProtocolError::ALL.len() + 1]
+};
+
+pub fn count(counter: &AtomicU64, amount: usize) {
+    counter.fetch_add(amount as u64, Ordering::Relaxed);
+}
+
+impl Metrics {
+    pub fn protocol_error(&self, error: ProtocolError) {
+        self.protocol_errors[error as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Serves GET /metrics (Prometheus text format) and GET /health on its own thread.
+// Requests are answered one at a time; scrapes are rare and cheap.
+pub fn spawn_endpoint(addr: &str, clients: Arc<Mutex<HashMap<Uuid, Client>>>, history: Arc<Mutex<History>>) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(%addr, error = %e, "Failed to start metrics endpoint");
+            return;
+        }
+    };
+    info!(%addr, "Serving /metrics and /health");
+    let started = Instant::now();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(stream, &clients, &history, started) {
+                        debug!(error = %e, "Metrics request failed");
+                    }
+                }
+                Err(e) => warn!(error = %e, "Metrics connection failed")
+            }
+        }
+    });
+}
+
+fn respond(mut stream: TcpStream, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, history: &Arc<Mutex<History>>, started: Instant) -> std::io::Result<()> {
+    let request = http::read_request(&stream)?;
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => http::respond(&mut stream, "200 OK", "text/plain; version=0.0.4", render(clients, history, started).as_bytes()),
+        ("GET", "/health") => http::respond(&mut stream, "200 OK", "text/plain", b"ok\n"),
+        ("GET", _) => http::not_found(&mut stream),
+        _ => http::method_not_allowed(&mut stream)
+    }
+}
+
+fn render(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, history: &Arc<Mutex<History>>, started: Instant) -> String {
+    // The two locks are never held together, so this can't deadlock against add_drawing
+    let (connected, synced, client_failures) = {
+        let clients = clients.lock().unwrap();
+        let failures: Vec<String> = clients.iter().map(|(id, client)| {
+            format!(
+                "psrs_client_write_failures_total{{id=\"{}\",name=\"{}\",addr=\"{}\"}} {}\n",
+                id, escape_label(&client.display_name()), client.addr, client.write_failures
+            )
+        }).collect();
+        (clients.len(), clients.values().filter(|client| client.has_history).count(), failures)
+    };
+    let (messages, history_bytes) = {
+        let history = history.lock().unwrap();
+        (history.history.len(), bincode::serialized_size(&history.history).unwrap_or(0))
+    };
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+        let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{value}");
+    };
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    metric("psrs_up", "gauge", "Whether the server is running.", String::from("psrs_up 1\n"));
+    metric("psrs_uptime_seconds", "gauge", "Seconds since the server started.", format!("psrs_uptime_seconds {}\n", started.elapsed().as_secs()));
+    metric("psrs_clients_connected", "gauge", "Open client connections.", format!("psrs_clients_connected {connected}\n"));
+    metric("psrs_clients_synced", "gauge", "Clients that have received the history and get broadcasts.", format!("psrs_clients_synced {synced}\n"));
+    metric("psrs_connections_total", "counter", "Connections accepted.", format!("psrs_connections_total {}\n", load(&METRICS.connections)));
+    metric("psrs_messages_received_total", "counter", "Messages read from clients.", format!("psrs_messages_received_total {}\n", load(&METRICS.messages_received)));
+    metric("psrs_messages_broadcast_total", "counter", "Messages broadcast to all clients.", format!("psrs_messages_broadcast_total {}\n", load(&METRICS.messages_broadcast)));
+    metric("psrs_bytes_in_total", "counter", "Bytes read from clients.", format!("psrs_bytes_in_total {}\n", load(&METRICS.bytes_in)));
+    metric("psrs_bytes_out_total", "counter", "Bytes written to clients.", format!("psrs_bytes_out_total {}\n", load(&METRICS.bytes_out)));
+    metric("psrs_write_failures_total", "counter", "Failed writes to clients, including ones that have since left.", format!("psrs_write_failures_total {}\n", load(&METRICS.write_failures)));
+    metric(
+        "psrs_protocol_errors_total", "counter", "Protocol errors sent back to clients, by error.",
+        ProtocolError::ALL.iter().map(|e| format!("psrs_protocol_errors_total{{error=\"{:?}\"}} {}\n", e, load(&METRICS.protocol_errors[*e as usize]))).collect()
+    );
+    metric("psrs_history_messages", "gauge", "Messages stored in the history.", format!("psrs_history_messages {messages}\n"));
+    metric("psrs_history_bytes", "gauge", "Serialized size of the history.", format!("psrs_history_bytes {history_bytes}\n"));
+    metric("psrs_client_write_failures_total", "counter", "Failed writes to each connected client.", client_failures.concat());
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
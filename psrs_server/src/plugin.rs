@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use psrs_protocol::canvas::BACKGROUND;
+use psrs_protocol::*;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{broadcast, Client};
+
+// Bots that live inside the server. Hooks run on the client's own thread with
+// no locks held, in the order the plugins were registered. Anything a plugin
+// wants to say goes through the Outbox and is sent once the hook returns.
+pub trait Plugin: Send + Sync {
+    fn name(&self) -> &str;
+
+    // After the client has received the history, so it will see anything sent to it
+    fn on_join(&self, _client: &ClientInfo, _out: &mut Outbox) {}
+
+    fn on_leave(&self, _client: &ClientInfo, _out: &mut Outbox) {}
+
+    // Called with a drawing that passed validation, before it's stored. The
+    // drawing may be changed in place; later plugins see the changed version.
+    fn on_drawing(&self, _client: &ClientInfo, _drawing: &mut TextureData, _out: &mut Outbox) -> Verdict {
+        Verdict::Allow
+    }
+}
+
+pub enum Verdict {
+    Allow,
+    // The reason is sent back to the author as an announcement
+    Reject(String)
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub id: Uuid,
+    pub addr: SocketAddr,
+    // Known once the client has said hello or sent a drawing
+    pub name: Option<String>
+}
+
+enum Action {
+    Reply(String),
+    Announce(String)
+}
+
+#[derive(Default)]
+pub struct Outbox {
+    actions: Vec<Action>
+}
+
+impl Outbox {
+    // An announcement only the client the hook is about will see
+    pub fn reply(&mut self, text: impl Into<String>) {
+        self.actions.push(Action::Reply(text.into()));
+    }
+
+    // An announcement for everyone
+    pub fn announce(&mut self, text: impl Into<String>) {
+        self.actions.push(Action::Announce(text.into()));
+    }
+}
+
+pub(crate) struct Plugins {
+    plugins: Vec<Arc<dyn Plugin>>
+}
+
+impl Plugins {
+    pub fn new(plugins: Vec<Arc<dyn Plugin>>) -> Plugins {
+        for plugin in &plugins {
+            info!(plugin = plugin.name(), "Loaded plugin");
+        }
+        Plugins { plugins }
+    }
+
+    pub fn client_joined(&self, client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>) {
+        let Some(info) = client_info(client_id, clients) else { return };
+        let mut out = Outbox::default();
+        for plugin in &self.plugins {
+            plugin.on_join(&info, &mut out);
+        }
+        deliver(client_id, clients, out);
+    }
+
+    // Called while the client is still in the map so replies have somewhere to go
+    pub fn client_left(&self, client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>) {
+        let Some(info) = client_info(client_id, clients) else { return };
+        let mut out = Outbox::default();
+        for plugin in &self.plugins {
+            plugin.on_leave(&info, &mut out);
+        }
+        deliver(client_id, clients, out);
+    }
+
+    // Returns the drawing to store, or None if a plugin turned it down
+    pub fn drawing_received(&self, client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, mut drawing: TextureData) -> Option<TextureData> {
+        let Some(info) = client_info(client_id, clients) else { return Some(drawing) };
+        let mut out = Outbox::default();
+        let mut allowed = true;
+        for plugin in &self.plugins {
+            if let Verdict::Reject(reason) = plugin.on_drawing(&info, &mut drawing, &mut out) {
+                warn!(plugin = plugin.name(), %reason, "Plugin rejected drawing");
+                out.reply(reason);
+                allowed = false;
+                break;
+            }
+        }
+        deliver(client_id, clients, out);
+        allowed.then_some(drawing)
+    }
+}
+
+// Relay links to other servers aren't clients as far as plugins are concerned
+fn client_info(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>) -> Option<ClientInfo> {
+    clients.lock().unwrap().get(&client_id).filter(|client| client.peer.is_none()).map(|client| ClientInfo {
+        id: client_id,
+        addr: client.addr,
+        name: client.name.map(|name| display_name(&name))
+    })
+}
+
+fn deliver(client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, out: Outbox) {
+    if out.actions.is_empty() {
+        return;
+    }
+    let mut clients = clients.lock().unwrap();
+    for action in out.actions {
+        match action {
+            Action::Reply(text) => {
+                if let Some(client) = clients.get_mut(&client_id) {
+                    client.send(&announcement(&text));
+                }
+            },
+            Action::Announce(text) => broadcast(&mut clients, &announcement(&text))
+        }
+    }
+}
+
+// Greets everyone who joins
+pub struct Welcome {
+    pub message: String
+}
+
+impl Plugin for Welcome {
+    fn name(&self) -> &str {
+        "welcome"
+    }
+
+    fn on_join(&self, _client: &ClientInfo, out: &mut Outbox) {
+        out.reply(self.message.clone());
+    }
+}
+
+// Turns away drawings with nothing drawn on them
+pub struct RejectBlank;
+
+impl Plugin for RejectBlank {
+    fn name(&self) -> &str {
+        "reject-blank"
+    }
+
+    fn on_drawing(&self, _client: &ClientInfo, drawing: &mut TextureData, _out: &mut Outbox) -> Verdict {
+        if drawing.data.iter().all(|pixel| *pixel == BACKGROUND) {
+            Verdict::Reject(String::from("Blank drawings aren't sent"))
+        } else {
+            Verdict::Allow
+        }
+    }
+}
+
+pub const DEFAULT_WELCOME: &str = "Welcome to PictoSend RS!";
+
+// Built-in plugins by name, as listed in PSRS_PLUGINS
+pub fn builtin(name: &str) -> Option<Arc<dyn Plugin>> {
+    match name {
+        "welcome" => Some(Arc::new(Welcome {
+            message: std::env::var("PSRS_WELCOME").unwrap_or_else(|_| String::from(DEFAULT_WELCOME))
+        })),
+        "reject-blank" => Some(Arc::new(RejectBlank)),
+        _ => None
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use psrs_protocol::*;
+use tracing::{debug, info, info_span, warn};
+use uuid::Uuid;
+
+use crate::board::Board;
+use crate::plugin::Plugins;
+use crate::{connection, handle_client, read_message, register, Client, History, ReadError, HANDSHAKE_TIMEOUT};
+
+// How many drawings we remember having relayed; plenty for anything still in flight
+const SEEN_CAPACITY: usize = 4096;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// Mirrors drawings between servers sharing a key. Every drawing is tagged with
+// the id of the server it was drawn on and the id it got there; a server drops
+// tags it has already seen (including its own), and never sends a drawing back
+// over the link it arrived on, so drawings can't go round in circles however
+// the servers are linked.
+pub(crate) struct Relay {
+    server_id: u64,
+    key: Option<String>,
+    seen: Mutex<Seen>
+}
+
+#[derive(Default)]
+struct Seen {
+    tags: HashSet<(u64, i32)>,
+    order: VecDeque<(u64, i32)>
+}
+
+impl Relay {
+    pub fn new(key: Option<String>) -> Relay {
+        Relay {
+            server_id: Uuid::new_v4().as_u64_pair().0,
+            key,
+            seen: Mutex::new(Seen::default())
+        }
+    }
+
+    fn hello(&self) -> Vec<u8> {
+        relay_hello(&RelayHello {
+            server_id: self.server_id,
+            key: self.key.clone().unwrap_or_default()
+        })
+    }
+
+    fn accepts(&self, hello: &RelayHello) -> bool {
+        self.key.as_ref().is_some_and(|key| *key == hello.key) && hello.server_id != self.server_id
+    }
+
+    // Remembers a tag, returning false if we've had it before
+    fn first_sight(&self, origin: u64, id: i32) -> bool {
+        if origin == self.server_id {
+            return false;
+        }
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.tags.insert((origin, id)) {
+            return false;
+        }
+        seen.order.push_back((origin, id));
+        if seen.order.len() > SEEN_CAPACITY {
+            let oldest = seen.order.pop_front().unwrap();
+            seen.tags.remove(&oldest);
+        }
+        true
+    }
+
+    // A client sent RelayHello: if it knows the key it becomes a peer and gets our hello back
+    pub fn peer_hello(&self, client_id: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, payload: &[u8]) -> Result<(), ProtocolError> {
+        let hello = decode_relay_hello(payload)?;
+        if !self.accepts(&hello) {
+            return Err(ProtocolError::UnexpectedMessage);
+        }
+        let mut clients = clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(&client_id) {
+            client.peer = Some(hello.server_id);
+            client.send(&self.hello());
+        }
+        info!(peer = hello.server_id, "Relay peer connected");
+        Ok(())
+    }
+
+    // Sends a drawing stored here to every peer
+    pub fn drawing_added(&self, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, stored: &TextureData) {
+        let packet = relay_drawing(self.server_id, stored);
+        forward(&mut clients.lock().unwrap(), &packet, None);
+    }
+
+    // A drawing from a peer. Returns it if it's new to us, after passing it on to
+    // the other peers; its id still needs replacing with one of ours.
+    pub fn drawing_relayed(&self, from: Uuid, clients: &Arc<Mutex<HashMap<Uuid, Client>>>, payload: &[u8]) -> Result<Option<TextureData>, ProtocolError> {
+        if clients.lock().unwrap().get(&from).is_none_or(|client| client.peer.is_none()) {
+            return Err(ProtocolError::UnexpectedMessage);
+        }
+        let (origin, drawing) = decode_relay_drawing(payload, now_millis())?;
+        if !self.first_sight(origin, drawing.id) {
+            debug!(origin, id = drawing.id, "Dropped relayed drawing we already have");
+            return Ok(None);
+        }
+        let mut packet = InfoData::new(InfoMsg::RelayDrawing, RELAY_DRAWING_SIZE as i32).to_bytes();
+        packet.extend_from_slice(payload);
+        forward(&mut clients.lock().unwrap(), &packet, Some(from));
+        Ok(Some(drawing))
+    }
+
+    // Keeps a link to a peer server open, reconnecting whenever it drops. The
+    // first attempt is made before returning so a reachable peer is linked
+    // by the time the server starts accepting clients.
+    pub fn spawn_link(self: &Arc<Relay>, peer: String, clients: Arc<Mutex<HashMap<Uuid, Client>>>, history: Arc<Mutex<History>>, plugins: Arc<Plugins>, board: Arc<Board>) {
+        let relay = Arc::clone(self);
+        let mut link = relay.dial(&peer, &clients);
+        thread::spawn(move || {
+            let span = info_span!("relay", %peer);
+            let _entered = span.enter();
+            loop {
+                match link {
+                    Ok((client_id, inbound, addr)) => {
+                        handle_client(client_id, inbound, addr, Arc::clone(&clients), Arc::clone(&history), Arc::clone(&plugins), Arc::clone(&relay), Arc::clone(&board));
+                        warn!("Relay link lost");
+                    },
+                    Err(e) => warn!(error = %e, "Failed to reach relay peer")
+                }
+                thread::sleep(RETRY_DELAY);
+                link = relay.dial(&peer, &clients);
+            }
+        });
+    }
+
+    fn dial(&self, peer: &str, clients: &Arc<Mutex<HashMap<Uuid, Client>>>) -> Result<(Uuid, connection::Inbound, SocketAddr), String> {
+        let addr = peer.to_socket_addrs().map_err(|e| e.to_string())?
+            .next()
+            .ok_or_else(|| String::from("no address"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).map_err(|e| e.to_string())?;
+        stream.write_all(&self.hello()).map_err(|e| e.to_string())?;
+
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).map_err(|e| e.to_string())?;
+        let hello = match read_message(&mut stream) {
+            Ok((info_data, payload)) if info_data.msg == InfoMsg::RelayHello => decode_relay_hello(&payload).map_err(|e| format!("{e:?}"))?,
+            // A peer that doesn't know our key answers with an error
+            Ok((info_data, _)) if info_data.msg == InfoMsg::Error => return Err(String::from("peer refused the link")),
+            Ok((info_data, _)) => return Err(format!("peer answered with {:?}", info_data.msg)),
+            Err(ReadError::Protocol(e)) => return Err(format!("{e:?}")),
+            Err(ReadError::Io(e)) => return Err(e.to_string())
+        };
+        if !self.accepts(&hello) {
+            return Err(String::from("peer doesn't know our key"));
+        }
+        stream.set_read_timeout(None).map_err(|e| e.to_string())?;
+
+        let (connection, inbound) = connection::tcp(stream).map_err(|e| e.to_string())?;
+        let client_id = register(clients, connection, addr);
+        if let Some(client) = clients.lock().unwrap().get_mut(&client_id) {
+            client.peer = Some(hello.server_id);
+        }
+        info!(peer = hello.server_id, "Linked to relay peer");
+        Ok((client_id, inbound, addr))
+    }
+}
+
+fn forward(clients: &mut HashMap<Uuid, Client>, packet: &[u8], except: Option<Uuid>) {
+    for (client_id, client) in clients.iter_mut() {
+        if client.peer.is_some() && Some(*client_id) != except {
+            client.send(packet);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+use psrs_protocol::canvas::canvas_png;
+use psrs_protocol::display_name;
+
+use crate::{gallery, http, History};
+
+// Each open page holds an event stream (and a thread) while it's open
+const MAX_EVENT_STREAMS: usize = 64;
+const EVENT_POLL: Duration = Duration::from_millis(500);
+const EVENT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+static EVENT_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+// Read-only view of the history for browsers: GET / lists the drawings newest
+// first, GET /drawing/<id>.png renders one and GET /events tells open pages
+// to reload when the history changes.
+pub fn spawn_viewer(addr: &str, history: Arc<Mutex<History>>) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(%addr, error = %e, "Failed to start web viewer");
+            return;
+        }
+    };
+    info!(%addr, "Serving web viewer");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let history = Arc::clone(&history);
+                    thread::spawn(move || {
+                        if let Err(e) = respond(stream, &history) {
+                            debug!(error = %e, "Web request failed");
+                        }
+                    });
+                }
+                Err(e) => warn!(error = %e, "Web connection failed")
+            }
+        }
+    });
+}
+
+fn respond(mut stream: TcpStream, history: &Arc<Mutex<History>>) -> std::io::Result<()> {
+    let request = http::read_request(&stream)?;
+    if request.method != "GET" {
+        return http::method_not_allowed(&mut stream);
+    }
+    match request.path.as_str() {
+        "/" => http::respond(&mut stream, "200 OK", "text/html; charset=utf-8", page(history).as_bytes()),
+        "/events" => events(stream, history),
+        path => {
+            let id = path.strip_prefix("/drawing/")
+                .and_then(|rest| rest.strip_suffix(".png"))
+                .and_then(|id| id.parse::<i32>().ok());
+            let data = id.and_then(|id| {
+                history.lock().unwrap().history.iter().find(|item| item.id == id).map(|item| item.data.clone())
+            });
+            match data {
+                Some(data) => http::respond(&mut stream, "200 OK", "image/png", &canvas_png(&data)),
+                None => http::not_found(&mut stream)
+            }
+        }
+    }
+}
+
+fn page(history: &Arc<Mutex<History>>) -> String {
+    let history = history.lock().unwrap();
+    let entries: String = history.history.iter().rev().map(|item| {
+        format!(
+            "<figure><img src=\"/drawing/{}.png\" width=\"200\" height=\"200\" alt=\"Drawing by {name}\"><figcaption><b>{name}</b><br><time>{}</time></figcaption></figure>\n",
+            item.id,
+            gallery::format_timestamp(item.timestamp),
+            name = gallery::escape_html(&display_name(&item.name))
+        )
+    }).collect();
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>PictoSend RS</title>\n<noscript><meta http-equiv=\"refresh\" content=\"30\"></noscript>\n<style>\n{}</style>\n</head>\n<body>\n<h1>PictoSend RS</h1>\n<p>{} messages, newest first</p>\n{}<script>new EventSource(\"/events\").onmessage = () => location.reload();</script>\n</body>\n</html>\n",
+        gallery::GALLERY_CSS,
+        history.history.len(),
+        entries
+    )
+}
+
+// Server-sent events: one "changed" event whenever the history revision moves on.
+// Comments are sent in between so a closed page is noticed and its thread ends.
+fn events(mut stream: TcpStream, history: &Arc<Mutex<History>>) -> std::io::Result<()> {
+    if EVENT_STREAMS.fetch_add(1, Ordering::SeqCst) >= MAX_EVENT_STREAMS {
+        EVENT_STREAMS.fetch_sub(1, Ordering::SeqCst);
+        return http::respond(&mut stream, "503 Service Unavailable", "text/plain", b"too many viewers\n");
+    }
+    let result = (|| {
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\nretry: 5000\n\n")?;
+        let mut seen = history.lock().unwrap().revision;
+        let mut last_write = Instant::now();
+        loop {
+            thread::sleep(EVENT_POLL);
+            let revision = history.lock().unwrap().revision;
+            if revision != seen {
+                seen = revision;
+                write!(stream, "data: changed\n\n")?;
+                last_write = Instant::now();
+            } else if last_write.elapsed() >= EVENT_KEEPALIVE {
+                write!(stream, ": keepalive\n\n")?;
+                last_write = Instant::now();
+            }
+        }
+    })();
+    EVENT_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    result
+}
@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::board::Board;
+use crate::plugin::Plugins;
+use crate::relay::Relay;
+use crate::{connection, handle_client, register, Client, History, HANDSHAKE_TIMEOUT};
+
+// Accepts browser clients over WebSocket. After the upgrade they join the same
+// clients map as TCP clients and are handled by the same code, so both kinds
+// see each other's drawings. Returns where it's listening, or None if it couldn't.
+pub fn spawn_gateway(addr: &str, clients: Arc<Mutex<HashMap<Uuid, Client>>>, history: Arc<Mutex<History>>, plugins: Arc<Plugins>, relay: Arc<Relay>, board: Arc<Board>) -> Option<SocketAddr> {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(%addr, error = %e, "Failed to start WebSocket gateway");
+            return None;
+        }
+    };
+    let local_addr = listener.local_addr().ok();
+    info!(%addr, "Accepting WebSocket clients");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let (stream, addr) = match stream.and_then(|stream| stream.peer_addr().map(|addr| (stream, addr))) {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(error = %e, "WebSocket connection failed");
+                    continue;
+                }
+            };
+            let clients = Arc::clone(&clients);
+            let history = Arc::clone(&history);
+            let plugins = Arc::clone(&plugins);
+            let relay = Arc::clone(&relay);
+            let board = Arc::clone(&board);
+            // The upgrade happens on the client's own thread so a slow one can't hold up the rest
+            thread::spawn(move || {
+                match connection::websocket(stream, HANDSHAKE_TIMEOUT) {
+                    Ok((connection, inbound)) => {
+                        let client_id = register(&clients, connection, addr);
+                        handle_client(client_id, inbound, addr, clients, history, plugins, relay, board);
+                    },
+                    Err(e) => warn!(%addr, error = %e, "WebSocket handshake failed")
+                }
+            });
+        }
+    });
+    local_addr
+}
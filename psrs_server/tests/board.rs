@@ -0,0 +1,150 @@
+mod common;
+
+use common::*;
+use psrs_protocol::canvas::{draw_stroke, Pen, Stroke, BACKGROUND};
+use psrs_protocol::*;
+
+fn join_board(client: &mut FakeClient) -> Vec<u8> {
+    client.send(&header(InfoMsg::JoinBoard, 0));
+    let info = client.read_header();
+    assert_eq!(info.msg, InfoMsg::Board);
+    client.read_exact(info.number as usize)
+}
+
+fn line(value: u8) -> Stroke {
+    Stroke { from: (0, 0), to: (200, 200), pen: Pen::Fat, value }
+}
+
+#[test]
+fn strokes_reach_everyone_on_the_board_and_stay_on_it() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    let (mut bob, _) = FakeClient::join(&server);
+    let (mut carol, _) = FakeClient::join(&server);
+    assert_eq!(join_board(&mut alice), vec![BACKGROUND; CANVAS_SIZE]);
+    join_board(&mut bob);
+
+    alice.send(&stroke(&line(40)));
+    let info = bob.read_header();
+    assert_eq!(info.msg, InfoMsg::Stroke);
+    assert_eq!(decode_stroke(&bob.read_exact(info.number as usize)).unwrap(), line(40));
+    alice.expect_quiet();
+    carol.expect_quiet();
+
+    // Joining late shows what's been drawn so far
+    let mut expected = vec![BACKGROUND; CANVAS_SIZE];
+    draw_stroke(&mut expected, &line(40));
+    assert_eq!(join_board(&mut carol), expected);
+
+    // Once off the board nothing more arrives
+    bob.send(&header(InfoMsg::LeaveBoard, 0));
+    // Leaving isn't answered, so give the server time to see it before carol draws
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    carol.send(&stroke(&line(80)));
+    assert_eq!(alice.read_header().msg, InfoMsg::Stroke);
+    bob.expect_quiet();
+}
+
+#[test]
+fn clearing_starts_everyone_afresh() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    let (mut bob, _) = FakeClient::join(&server);
+    join_board(&mut alice);
+    join_board(&mut bob);
+
+    alice.send(&stroke(&line(40)));
+    bob.read_header();
+    bob.read_exact(STROKE_SIZE);
+    bob.send(&header(InfoMsg::ClearBoard, 0));
+    assert_eq!(alice.read_header().msg, InfoMsg::ClearBoard);
+    alice.send(&header(InfoMsg::LeaveBoard, 0));
+    assert_eq!(join_board(&mut alice), vec![BACKGROUND; CANVAS_SIZE]);
+}
+
+#[test]
+fn posting_puts_the_board_in_the_history() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    let (mut bob, _) = FakeClient::join(&server);
+    join_board(&mut alice);
+    join_board(&mut bob);
+
+    alice.send(&stroke(&line(40)));
+    bob.read_header();
+    bob.read_exact(STROKE_SIZE);
+    let mut expected = vec![BACKGROUND; CANVAS_SIZE];
+    draw_stroke(&mut expected, &line(40));
+
+    // Everyone on the board starts afresh as it's taken, the poster too
+    alice.send(&post_board(&pad_name("alice").unwrap()));
+    for client in [&mut alice, &mut bob] {
+        assert_eq!(client.read_header().msg, InfoMsg::ClearBoard);
+        let posted = client.read_drawing();
+        assert_eq!(posted.id, 1);
+        assert_eq!(display_name(&posted.name), "alice");
+        assert_eq!(posted.data, expected);
+    }
+
+    let (_carol, history) = FakeClient::join(&server);
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].data, expected);
+}
+
+#[test]
+fn the_board_is_posted_under_the_posters_own_name() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    join_board(&mut alice);
+    alice.send_drawing(&drawing("alice", 1));
+    alice.read_drawing();
+
+    alice.send(&post_board(&pad_name("bob").unwrap()));
+    alice.expect_error(ProtocolError::BadName);
+    alice.expect_quiet();
+    assert_eq!(alice.sync().len(), 1);
+}
+
+#[test]
+fn strokes_past_the_limit_are_dropped() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    let (mut bob, _) = FakeClient::join(&server);
+    join_board(&mut alice);
+    join_board(&mut bob);
+
+    let mut burst = Vec::new();
+    for _ in 0..300 {
+        burst.extend(stroke(&line(40)));
+    }
+    alice.send(&burst);
+    for _ in 0..240 {
+        assert_eq!(bob.read_header().msg, InfoMsg::Stroke);
+        bob.read_exact(STROKE_SIZE);
+    }
+    bob.expect_quiet();
+    alice.expect_quiet();
+}
+
+#[test]
+fn the_board_is_only_for_those_on_it() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+
+    alice.send(&stroke(&line(40)));
+    alice.expect_error(ProtocolError::UnexpectedMessage);
+    alice.send(&header(InfoMsg::ClearBoard, 0));
+    alice.expect_error(ProtocolError::UnexpectedMessage);
+    alice.send(&post_board(&pad_name("alice").unwrap()));
+    alice.expect_error(ProtocolError::UnexpectedMessage);
+
+    // Strokes off the canvas are refused, but the client stays on the board
+    join_board(&mut alice);
+    let mut packet = header(InfoMsg::Stroke, STROKE_SIZE as i32);
+    packet.extend([201, 0, 0, 0, 0, 0, 0, 0, 40]);
+    alice.send(&packet);
+    alice.expect_error(ProtocolError::Malformed);
+    alice.send(&stroke(&line(40)));
+    alice.expect_quiet();
+    assert!(alice.sync().is_empty());
+}
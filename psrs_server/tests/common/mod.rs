@@ -0,0 +1,177 @@
+// Shared by the integration tests: a server running in this process and fake
+// clients that speak the wire protocol byte by byte, so tests can send things
+// a well-behaved client never would.
+#![allow(dead_code)]
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use psrs_protocol::*;
+use psrs_server::{Config, Server};
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Stopped (clients told, threads wound down) when dropped
+pub struct TestServer {
+    pub addr: SocketAddr,
+    // Set when the config asks for a WebSocket gateway
+    pub ws_addr: Option<SocketAddr>,
+    shutting_down: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>
+}
+
+// An ephemeral port on loopback, history in memory and nothing else listening
+pub fn config() -> Config {
+    Config {
+        addr: String::from("127.0.0.1:0"),
+        history_path: None,
+        metrics_addr: None,
+        discovery_addr: None,
+        ..Config::default()
+    }
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        TestServer::with_config(config())
+    }
+
+    pub fn with_config(config: Config) -> TestServer {
+        let server = Server::bind(&config).unwrap();
+        let addr = server.local_addr();
+        let ws_addr = server.ws_addr();
+        let shutting_down = server.shutdown_flag();
+        let thread = thread::spawn(move || server.run());
+        TestServer { addr, ws_addr, shutting_down, thread: Some(thread) }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub fn drawing(name: &str, value: u8) -> TextureData {
+    TextureData {
+        name: pad_name(name).unwrap(),
+        data: vec![value; CANVAS_SIZE],
+        request_history: false,
+        request_history_length: false,
+        history_length: 0,
+        confirm_history: false,
+        timestamp: now_millis(),
+        id: 0,
+        reply_to: 0
+    }
+}
+
+pub fn drawing_packet(texture_data: &TextureData) -> Vec<u8> {
+    let mut packet = InfoData::new(InfoMsg::Drawing, PACKET_SIZE as i32).to_bytes();
+    packet.extend(bincode::serialize(texture_data).unwrap());
+    packet
+}
+
+pub fn header(msg: InfoMsg, number: i32) -> Vec<u8> {
+    InfoData::new(msg, number).to_bytes()
+}
+
+pub struct FakeClient {
+    stream: TcpStream
+}
+
+impl FakeClient {
+    pub fn connect(server: &TestServer) -> FakeClient {
+        let stream = TcpStream::connect(server.addr).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        FakeClient { stream }
+    }
+
+    // Connects and does the history handshake
+    pub fn join(server: &TestServer) -> (FakeClient, Vec<TextureData>) {
+        let mut client = FakeClient::connect(server);
+        let history = client.sync();
+        (client, history)
+    }
+
+    // Says hello as name with key first, like a real client
+    pub fn join_as(server: &TestServer, name: &str, key: [u8; KEY_SIZE]) -> (FakeClient, Vec<TextureData>) {
+        let mut client = FakeClient::connect(server);
+        client.send(&hello(&Hello { name: pad_name(name).unwrap(), key }));
+        let history = client.sync();
+        (client, history)
+    }
+
+    pub fn send(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).unwrap();
+    }
+
+    pub fn send_drawing(&mut self, texture_data: &TextureData) {
+        self.send(&drawing_packet(texture_data));
+    }
+
+    pub fn read_exact(&mut self, len: usize) -> Vec<u8> {
+        let mut buffer = vec![0; len];
+        self.stream.read_exact(&mut buffer).unwrap();
+        buffer
+    }
+
+    pub fn read_header(&mut self) -> InfoData {
+        decode_header(&self.read_exact(INFO_SIZE)).unwrap()
+    }
+
+    pub fn sync(&mut self) -> Vec<TextureData> {
+        self.send(&header(InfoMsg::RequestHistoryLength, 0));
+        let length = self.read_header();
+        assert_eq!(length.msg, InfoMsg::HistoryLength);
+        self.send(&header(InfoMsg::RequestHistory, 0));
+        let history = bincode::deserialize(&self.read_exact(length.number as usize)).unwrap();
+        self.send(&header(InfoMsg::ConfirmReceivedHistory, 0));
+        history
+    }
+
+    pub fn read_drawing(&mut self) -> TextureData {
+        let info = self.read_header();
+        assert_eq!(info.msg, InfoMsg::Drawing, "expected a drawing");
+        decode_drawing(&self.read_exact(info.number as usize), now_millis()).unwrap()
+    }
+
+    pub fn expect_error(&mut self, error: ProtocolError) {
+        let info = self.read_header();
+        assert_eq!(info.msg, InfoMsg::Error);
+        assert_eq!(ProtocolError::from_code(info.number), Some(error));
+    }
+
+    pub fn expect_refused(&mut self, error: ProtocolError) {
+        let info = self.read_header();
+        assert_eq!(info.msg, InfoMsg::DrawingRefused);
+        assert_eq!(ProtocolError::from_code(info.number), Some(error));
+    }
+
+    // Nothing arrives for a while
+    pub fn expect_quiet(&mut self) {
+        self.stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let mut byte = [0];
+        match self.stream.read(&mut byte) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {},
+            other => panic!("expected nothing, got {other:?}")
+        }
+        self.stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    }
+
+    // The server hung up on us
+    pub fn expect_closed(&mut self) {
+        let mut byte = [0];
+        match self.stream.read(&mut byte) {
+            Ok(0) => {},
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => {},
+            other => panic!("expected the connection to close, got {other:?}")
+        }
+    }
+}
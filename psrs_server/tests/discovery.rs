@@ -0,0 +1,55 @@
+mod common;
+
+use std::net::UdpSocket;
+use std::time::Duration;
+use common::{FakeClient, TestServer};
+use psrs_protocol::discovery::*;
+use psrs_server::Config;
+
+fn free_udp_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[test]
+fn servers_answer_discovery_queries() {
+    let port = free_udp_port();
+    let server = TestServer::with_config(Config {
+        name: String::from("Office"),
+        discovery_addr: Some(format!("0.0.0.0:{port}")),
+        ..common::config()
+    });
+    let (_alice, _) = FakeClient::join(&server);
+
+    let found = psrs_client::discover(port, Duration::from_millis(500)).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].addr.port(), server.addr.port());
+    assert_eq!(found[0].info.name, "Office");
+    assert_eq!((found[0].info.port, found[0].info.clients, found[0].info.messages), (server.addr.port(), 1, 0));
+}
+
+#[test]
+fn other_datagrams_are_ignored() {
+    let port = free_udp_port();
+    let _server = TestServer::with_config(Config {
+        discovery_addr: Some(format!("127.0.0.1:{port}")),
+        ..common::config()
+    });
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    socket.send_to(b"hello", ("127.0.0.1", port)).unwrap();
+    assert!(socket.recv_from(&mut [0; 64]).is_err());
+
+    // Still answering afterwards
+    socket.send_to(&query(), ("127.0.0.1", port)).unwrap();
+    let mut buffer = [0; 512];
+    let (len, _) = socket.recv_from(&mut buffer).unwrap();
+    assert!(decode_reply(&buffer[..len]).is_some());
+}
+
+#[test]
+fn long_names_are_cut_short() {
+    let info = ServerInfo { id: 1, name: "é".repeat(MAX_SERVER_NAME_SIZE), port: 6969, clients: 0, messages: 0 };
+    let decoded = decode_reply(&reply(&info)).unwrap();
+    assert_eq!(decoded.name, "é".repeat(MAX_SERVER_NAME_SIZE / 2));
+    assert!(decode_reply(&query()).is_none());
+}
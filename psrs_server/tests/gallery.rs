@@ -0,0 +1,45 @@
+mod common;
+
+use psrs_server::gallery::*;
+use psrs_protocol::canvas::*;
+use psrs_protocol::*;
+
+fn drawing(id: i32, name: &str, timestamp: u128) -> TextureData {
+    let mut data = vec![BACKGROUND; CANVAS_SIZE];
+    data[0] = 255;
+    data[1] = 0;
+    TextureData { data, timestamp, id, ..common::drawing(name, BACKGROUND) }
+}
+
+#[test]
+fn timestamps_are_formatted_in_utc() {
+    assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+    assert_eq!(format_timestamp(1_710_000_000_000), "2024-03-09 16:00:00 UTC");
+    assert_eq!(format_timestamp(951_782_400_999), "2000-02-29 00:00:00 UTC");
+}
+
+#[test]
+fn background_renders_white_and_ink_renders_dark() {
+    let image = canvas_image(&drawing(1, "alice", 0).data);
+    assert_eq!(image.dimensions(), (CANVAS_WIDTH, CANVAS_HEIGHT));
+    assert_eq!(image.get_pixel(0, 0).0, [0]);
+    assert_eq!(image.get_pixel(1, 0).0, [255]);
+    assert_eq!(image.get_pixel(100, 100).0, [255]);
+    assert!(canvas_png(&drawing(1, "alice", 0).data).starts_with(b"\x89PNG"));
+}
+
+#[test]
+fn export_writes_pngs_and_an_escaped_index() {
+    let dir = std::env::temp_dir().join(format!("psrs_gallery_{}", std::process::id()));
+    let history = vec![drawing(3, "alice", 1_710_000_000_000), drawing(7, "<b>bob</b>", 1_710_000_060_000)];
+    export(&history, &dir).unwrap();
+
+    assert!(dir.join("3.png").exists());
+    assert!(dir.join("7.png").exists());
+    let index = std::fs::read_to_string(dir.join("index.html")).unwrap();
+    assert!(index.contains("2024-03-09 16:01:00 UTC"));
+    assert!(index.contains("&lt;b&gt;bob&lt;/b&gt;"));
+    assert!(!index.contains("<b>bob"));
+    assert!(index.find("3.png").unwrap() < index.find("7.png").unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,355 @@
+mod common;
+
+use common::*;
+use psrs_protocol::*;
+
+#[test]
+fn joining_an_empty_server_gets_empty_history() {
+    let server = TestServer::start();
+    let (_client, history) = FakeClient::join(&server);
+    assert!(history.is_empty());
+}
+
+#[test]
+fn history_is_synced_in_order_and_capped() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    for value in 0..57 {
+        alice.send_drawing(&drawing("alice", value));
+        assert_eq!(alice.read_drawing().id, value as i32 + 1);
+    }
+
+    // Only the newest 56 are kept
+    let (_bob, history) = FakeClient::join(&server);
+    assert_eq!(history.len(), 56);
+    assert_eq!(history[0].id, 2);
+    assert_eq!(history[55].id, 57);
+    assert!(history.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+    assert_eq!(history[55].data, vec![56; CANVAS_SIZE]);
+}
+
+#[test]
+fn drawings_fan_out_to_every_synced_client() {
+    let server = TestServer::start();
+    let mut joined: Vec<FakeClient> = (0..3).map(|_| FakeClient::join(&server).0).collect();
+    // Connected but hasn't asked for the history, so isn't sent anything yet
+    let mut lurker = FakeClient::connect(&server);
+
+    joined[1].send_drawing(&drawing("bob", 9));
+    for client in &mut joined {
+        let received = client.read_drawing();
+        assert_eq!(received.id, 1);
+        assert_eq!(display_name(&received.name), "bob");
+        assert_eq!(received.data, vec![9; CANVAS_SIZE]);
+    }
+    lurker.expect_quiet();
+
+    // Once it syncs it finds the drawing in the history instead
+    assert_eq!(lurker.sync().len(), 1);
+}
+
+#[test]
+fn activity_reaches_everyone_else_and_is_not_stored() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join_as(&server, "alice", [1; KEY_SIZE]);
+    let (mut bob, _) = FakeClient::join(&server);
+    let mut lurker = FakeClient::connect(&server);
+    // Once this reaches alice the server has bob down as joined
+    bob.send_drawing(&drawing("bob", 1));
+    alice.read_drawing();
+    bob.read_drawing();
+
+    // It goes by the name she said hello with, whatever it says, before she has drawn anything
+    let name = pad_name("alice").unwrap();
+    alice.send(&activity(&pad_name("carol").unwrap()));
+    let info = bob.read_header();
+    assert_eq!(info.msg, InfoMsg::Activity);
+    assert_eq!(decode_activity(&bob.read_exact(info.number as usize)).unwrap(), name);
+    // A second one straight away is dropped rather than passed on
+    alice.send(&activity(&name));
+    bob.expect_quiet();
+    alice.expect_quiet();
+    lurker.expect_quiet();
+
+    // Nobody can say who's drawing for a client that hasn't said hello or drawn
+    let mut carol = FakeClient::join(&server).0;
+    carol.send(&activity(&pad_name("carol").unwrap()));
+    alice.expect_quiet();
+
+    alice.send(&activity(&[0; NAME_SIZE]));
+    alice.expect_error(ProtocolError::BadName);
+    assert_eq!(lurker.sync().len(), 1);
+}
+
+#[test]
+fn disconnecting_clients_dont_disturb_the_rest() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    let (bob, _) = FakeClient::join(&server);
+    drop(bob);
+
+    // Hanging up halfway through the handshake releases the history straight away
+    let mut carol = FakeClient::connect(&server);
+    carol.send(&header(InfoMsg::RequestHistoryLength, 0));
+    assert_eq!(carol.read_header().msg, InfoMsg::HistoryLength);
+    drop(carol);
+
+    alice.send_drawing(&drawing("alice", 1));
+    assert_eq!(alice.read_drawing().id, 1);
+    let (_dave, history) = FakeClient::join(&server);
+    assert_eq!(history.len(), 1);
+}
+
+#[test]
+fn whatever_changes_during_the_handshake_follows_it() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    alice.send_drawing(&drawing("alice", 1));
+    alice.read_drawing();
+
+    // Bob stops halfway, and alice isn't held up meanwhile
+    let mut bob = FakeClient::connect(&server);
+    bob.send(&header(InfoMsg::RequestHistoryLength, 0));
+    let length = bob.read_header();
+    alice.send(&header(InfoMsg::DeleteMessage, 1));
+    assert_eq!(alice.read_header().msg, InfoMsg::MessageDeleted);
+    alice.send_drawing(&drawing("alice", 2));
+    assert_eq!(alice.read_drawing().id, 2);
+
+    // He's sent the history as it was, then told what became of it
+    bob.send(&header(InfoMsg::RequestHistory, 0));
+    let history: Vec<TextureData> = bincode::deserialize(&bob.read_exact(length.number as usize)).unwrap();
+    assert_eq!(history.iter().map(|item| item.id).collect::<Vec<_>>(), [1]);
+    bob.send(&header(InfoMsg::ConfirmReceivedHistory, 0));
+    assert_eq!(bob.read_drawing().id, 2);
+    let deleted = bob.read_header();
+    assert_eq!((deleted.msg, deleted.number), (InfoMsg::MessageDeleted, 1));
+    bob.expect_quiet();
+}
+
+#[test]
+fn deleting_is_only_for_the_author() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    let (mut bob, _) = FakeClient::join(&server);
+    alice.send_drawing(&drawing("alice", 1));
+    alice.read_drawing();
+    bob.read_drawing();
+
+    bob.send(&header(InfoMsg::DeleteMessage, 1));
+    let refused = bob.read_header();
+    assert_eq!((refused.msg, refused.number), (InfoMsg::DeleteRefused, 1));
+
+    alice.send(&header(InfoMsg::DeleteMessage, 1));
+    for client in [&mut alice, &mut bob] {
+        let deleted = client.read_header();
+        assert_eq!((deleted.msg, deleted.number), (InfoMsg::MessageDeleted, 1));
+    }
+}
+
+#[test]
+fn a_connection_keeps_the_name_it_first_drew_under() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    let (mut mallory, _) = FakeClient::join(&server);
+    alice.send_drawing(&drawing("alice", 1));
+    alice.read_drawing();
+    mallory.read_drawing();
+
+    mallory.send_drawing(&drawing("mallory", 2));
+    mallory.read_drawing();
+    alice.read_drawing();
+    // Drawing as alice afterwards doesn't make it alice's to delete
+    mallory.send_drawing(&drawing("alice", 3));
+    mallory.expect_refused(ProtocolError::BadName);
+    mallory.send(&header(InfoMsg::DeleteMessage, 1));
+    let refused = mallory.read_header();
+    assert_eq!((refused.msg, refused.number), (InfoMsg::DeleteRefused, 1));
+    alice.expect_quiet();
+}
+
+#[test]
+fn deleting_goes_by_the_key_said_hello_with() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join_as(&server, "alice", [1; KEY_SIZE]);
+    alice.send_drawing(&drawing("alice", 1));
+    alice.read_drawing();
+    // Once said, the name sticks
+    alice.send(&hello(&Hello { name: pad_name("carol").unwrap(), key: [3; KEY_SIZE] }));
+    alice.expect_error(ProtocolError::UnexpectedMessage);
+    alice.send_drawing(&drawing("carol", 2));
+    alice.expect_refused(ProtocolError::BadName);
+    drop(alice);
+
+    // Anyone can call themselves alice, but without her key her messages aren't theirs
+    let (mut mallory, _) = FakeClient::join_as(&server, "alice", [2; KEY_SIZE]);
+    mallory.send_drawing(&drawing("alice", 3));
+    assert_eq!(mallory.read_drawing().id, 2);
+    mallory.send(&header(InfoMsg::DeleteMessage, 1));
+    let refused = mallory.read_header();
+    assert_eq!((refused.msg, refused.number), (InfoMsg::DeleteRefused, 1));
+
+    // Coming back with the same key she can delete hers before drawing anything new
+    let (mut alice, _) = FakeClient::join_as(&server, "alice", [1; KEY_SIZE]);
+    alice.send(&header(InfoMsg::DeleteMessage, 2));
+    assert_eq!(alice.read_header().msg, InfoMsg::DeleteRefused);
+    alice.send(&header(InfoMsg::DeleteMessage, 1));
+    for client in [&mut alice, &mut mallory] {
+        let deleted = client.read_header();
+        assert_eq!((deleted.msg, deleted.number), (InfoMsg::MessageDeleted, 1));
+    }
+}
+
+#[test]
+fn framing_errors_are_reported_then_disconnected() {
+    let server = TestServer::start();
+
+    let mut unknown = header(InfoMsg::Nothing, 0);
+    unknown[0] = 200;
+    let mut wrong_length = header(InfoMsg::Drawing, 12);
+    wrong_length.extend([0; 12]);
+    let oversized = header(InfoMsg::Drawing, i32::MAX);
+
+    for (bytes, error) in [(unknown, ProtocolError::UnknownMessage), (wrong_length, ProtocolError::Malformed), (oversized, ProtocolError::TooLarge)] {
+        let (mut client, _) = FakeClient::join(&server);
+        client.send(&bytes);
+        client.expect_error(error);
+        client.expect_closed();
+    }
+}
+
+#[test]
+fn bad_drawings_are_refused_but_the_client_stays() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+
+    let mut bad_name = drawing("alice", 1);
+    bad_name.name[0] = b'\n';
+    alice.send_drawing(&bad_name);
+    alice.expect_refused(ProtocolError::BadName);
+
+    let mut bad_timestamp = drawing("alice", 1);
+    bad_timestamp.timestamp = 1;
+    alice.send_drawing(&bad_timestamp);
+    alice.expect_refused(ProtocolError::BadTimestamp);
+
+    // Messages only the server sends
+    alice.send(&header(InfoMsg::HistoryLength, 0));
+    alice.expect_error(ProtocolError::UnexpectedMessage);
+
+    alice.send_drawing(&drawing("alice", 2));
+    assert_eq!(alice.read_drawing().id, 1);
+}
+
+#[test]
+fn handshake_out_of_order_is_refused() {
+    let server = TestServer::start();
+    let mut client = FakeClient::connect(&server);
+    client.send(&header(InfoMsg::RequestHistoryLength, 0));
+    assert_eq!(client.read_header().msg, InfoMsg::HistoryLength);
+    client.send(&header(InfoMsg::ConfirmReceivedHistory, 0));
+    client.expect_error(ProtocolError::UnexpectedMessage);
+
+    // Still connected, and a proper handshake works afterwards
+    assert!(client.sync().is_empty());
+}
+
+#[test]
+fn stopping_the_server_tells_clients() {
+    let server = TestServer::start();
+    let (mut client, _) = FakeClient::join(&server);
+    drop(server);
+    assert_eq!(client.read_header().msg, InfoMsg::ServerShutdown);
+    client.expect_closed();
+}
+
+#[test]
+fn replies_keep_the_drawing_they_answer() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    let (mut bob, _) = FakeClient::join(&server);
+    alice.send_drawing(&drawing("alice", 1));
+    assert_eq!(bob.read_drawing().id, 1);
+    alice.read_drawing();
+
+    let mut reply = drawing("bob", 2);
+    reply.reply_to = 1;
+    bob.send_drawing(&reply);
+    let received = alice.read_drawing();
+    assert_eq!((received.id, received.reply_to), (2, 1));
+    bob.read_drawing();
+
+    // Nothing here has that id yet, so it's stored as a plain drawing
+    reply.reply_to = 40;
+    bob.send_drawing(&reply);
+    assert_eq!(alice.read_drawing().reply_to, 0);
+    bob.read_drawing();
+
+    reply.reply_to = -1;
+    bob.send_drawing(&reply);
+    bob.expect_refused(ProtocolError::Malformed);
+    assert_eq!(bob.sync().iter().map(|item| item.reply_to).collect::<Vec<_>>(), [0, 1, 0]);
+}
+
+fn read_counts(client: &mut FakeClient) -> ReactionCounts {
+    let info = client.read_header();
+    assert_eq!(info.msg, InfoMsg::ReactionCounts);
+    decode_reaction_counts(&client.read_exact(info.number as usize)).unwrap()
+}
+
+#[test]
+fn reactions_are_counted_taken_back_and_synced() {
+    let server = TestServer::start();
+    let (mut alice, _) = FakeClient::join(&server);
+    let (mut bob, _) = FakeClient::join(&server);
+    alice.send_drawing(&drawing("alice", 1));
+    alice.read_drawing();
+    bob.read_drawing();
+
+    bob.send_drawing(&drawing("bob", 2));
+    alice.read_drawing();
+    bob.read_drawing();
+
+    let star = React { message_id: 1, reaction: Reaction::Star };
+    alice.send(&react(&star));
+    for client in [&mut alice, &mut bob] {
+        assert_eq!(read_counts(client).counts, [0, 1, 0, 0]);
+    }
+    bob.send(&react(&star));
+    bob.send(&react(&React { reaction: Reaction::Huh, ..star }));
+    for client in [&mut alice, &mut bob] {
+        assert_eq!(read_counts(client).counts, [0, 2, 0, 0]);
+        assert_eq!(read_counts(client).counts, [0, 2, 0, 1]);
+    }
+
+    // Stamping the same one again takes it back
+    alice.send(&react(&star));
+    assert_eq!(read_counts(&mut bob).counts, [0, 1, 0, 1]);
+    read_counts(&mut alice);
+
+    // Nothing to react to
+    alice.send(&react(&React { message_id: 40, ..star }));
+    alice.expect_quiet();
+
+    // Someone joining later gets the totals straight after the history, and
+    // can't react until the server knows who they are
+    let (mut carol, history) = FakeClient::join(&server);
+    assert_eq!(history.len(), 2);
+    let counts = read_counts(&mut carol);
+    assert_eq!((counts.message_id, counts.count(Reaction::Star), counts.count(Reaction::Huh)), (1, 1, 1));
+    carol.send(&react(&star));
+    carol.expect_error(ProtocolError::UnexpectedMessage);
+
+    // Saying hello is enough, without drawing anything
+    let (mut dan, _) = FakeClient::join_as(&server, "dan", [4; KEY_SIZE]);
+    read_counts(&mut dan);
+    dan.send(&react(&star));
+    assert_eq!(read_counts(&mut dan).counts, [0, 2, 0, 1]);
+    read_counts(&mut alice);
+
+    // Deleting the message takes its reactions with it
+    alice.send(&header(InfoMsg::DeleteMessage, 1));
+    assert_eq!(alice.read_header().msg, InfoMsg::MessageDeleted);
+    let (mut erin, _) = FakeClient::join(&server);
+    erin.expect_quiet();
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use std::path::PathBuf;
+use common::*;
+use psrs_protocol::*;
+use psrs_server::load_history;
+
+fn scratch_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("psrs-{name}-{}", std::process::id()))
+}
+
+#[test]
+fn history_from_before_message_ids_still_loads() {
+    let history = load_history(&PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("history")).unwrap();
+    assert!(!history.is_empty());
+    assert!(history.iter().enumerate().all(|(index, item)| item.id == index as i32 + 1 && item.reply_to == 0));
+}
+
+#[test]
+fn version_1_history_loads_without_replies() {
+    let item = drawing("alice", 3);
+    let v1 = vec![(item.name, item.data.clone(), false, false, 0i32, false, item.timestamp, 7i32)];
+    let mut bytes = b"PSRSHIST".to_vec();
+    bytes.extend(bincode::serialize(&(1u32, v1)).unwrap());
+    let path = scratch_path("v1-history");
+    std::fs::write(&path, bytes).unwrap();
+
+    let history = load_history(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!((history[0].id, history[0].reply_to), (7, 0));
+    assert_eq!(history[0].data, item.data);
+}
+
+#[test]
+fn version_2_history_loads_without_reactions() {
+    let mut item = drawing("alice", 3);
+    item.id = 4;
+    item.reply_to = 2;
+    let mut bytes = b"PSRSHIST".to_vec();
+    bytes.extend(bincode::serialize(&(2u32, vec![item])).unwrap());
+    let path = scratch_path("v2-history");
+    std::fs::write(&path, bytes).unwrap();
+
+    let history = load_history(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(history.iter().map(|item| (item.id, item.reply_to)).collect::<Vec<_>>(), [(4, 2)]);
+}
+
+#[test]
+fn version_3_history_loads_without_owners() {
+    let mut item = drawing("alice", 3);
+    item.id = 4;
+    let reactions = std::collections::BTreeMap::from([(4i32, vec![(Reaction::Star, item.name)])]);
+    let mut bytes = b"PSRSHIST".to_vec();
+    bytes.extend(bincode::serialize(&(3u32, vec![item], reactions)).unwrap());
+    let path = scratch_path("v3-history");
+    std::fs::write(&path, bytes).unwrap();
+
+    let history = load_history(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(history.iter().map(|item| item.id).collect::<Vec<_>>(), [4]);
+}
+
+#[test]
+fn replies_are_saved_with_the_history() {
+    let path = scratch_path("saved-history");
+    let server = TestServer::with_config(psrs_server::Config { history_path: Some(path.clone()), ..config() });
+    let (mut alice, _) = FakeClient::join(&server);
+    alice.send_drawing(&drawing("alice", 1));
+    alice.read_drawing();
+    let mut reply = drawing("alice", 2);
+    reply.reply_to = 1;
+    alice.send_drawing(&reply);
+    alice.read_drawing();
+    drop(server);
+
+    let history = load_history(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(history.iter().map(|item| (item.id, item.reply_to)).collect::<Vec<_>>(), [(1, 0), (2, 1)]);
+}
+
+#[test]
+fn reactions_are_saved_with_the_history() {
+    let path = scratch_path("saved-reactions");
+    let server = TestServer::with_config(psrs_server::Config { history_path: Some(path.clone()), ..config() });
+    let (mut alice, _) = FakeClient::join(&server);
+    alice.send_drawing(&drawing("alice", 1));
+    alice.read_drawing();
+    alice.send(&react(&React { message_id: 1, reaction: Reaction::Plus }));
+    assert_eq!(alice.read_header().msg, InfoMsg::ReactionCounts);
+    drop(server);
+
+    let server = TestServer::with_config(psrs_server::Config { history_path: Some(path.clone()), ..config() });
+    let (mut bob, _) = FakeClient::join(&server);
+    let info = bob.read_header();
+    assert_eq!(info.msg, InfoMsg::ReactionCounts);
+    let counts = decode_reaction_counts(&bob.read_exact(info.number as usize)).unwrap();
+    drop(server);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!((counts.message_id, counts.count(Reaction::Plus)), (1, 1));
+}
+
+#[test]
+fn owners_are_saved_with_the_history() {
+    let path = scratch_path("saved-owners");
+    let server = TestServer::with_config(psrs_server::Config { history_path: Some(path.clone()), ..config() });
+    let (mut alice, _) = FakeClient::join_as(&server, "alice", [1; KEY_SIZE]);
+    alice.send_drawing(&drawing("alice", 1));
+    alice.read_drawing();
+    drop(server);
+
+    // After a restart only alice's key can delete it
+    let server = TestServer::with_config(psrs_server::Config { history_path: Some(path.clone()), ..config() });
+    let (mut mallory, _) = FakeClient::join_as(&server, "alice", [2; KEY_SIZE]);
+    mallory.send(&header(InfoMsg::DeleteMessage, 1));
+    assert_eq!(mallory.read_header().msg, InfoMsg::DeleteRefused);
+    let (mut alice, _) = FakeClient::join_as(&server, "alice", [1; KEY_SIZE]);
+    alice.send(&header(InfoMsg::DeleteMessage, 1));
+    assert_eq!(alice.read_header().msg, InfoMsg::MessageDeleted);
+    drop(server);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use std::sync::Arc;
+use common::{TestServer, TIMEOUT};
+use psrs_client::{Client, Event};
+use psrs_protocol::canvas::BACKGROUND;
+use psrs_protocol::*;
+use psrs_server::plugin::{ClientInfo, Outbox, Plugin, RejectBlank, Verdict, Welcome};
+use psrs_server::Config;
+
+// Stamps a dot in the corner of every drawing, refuses anyone called "spammer"
+// and says goodbye for people who leave
+struct Moderator;
+
+impl Plugin for Moderator {
+    fn name(&self) -> &str {
+        "moderator"
+    }
+
+    fn on_leave(&self, client: &ClientInfo, out: &mut Outbox) {
+        if let Some(name) = &client.name {
+            out.announce(format!("{name} left"));
+        }
+    }
+
+    fn on_drawing(&self, _client: &ClientInfo, drawing: &mut TextureData, _out: &mut Outbox) -> Verdict {
+        if display_name(&drawing.name) == "spammer" {
+            return Verdict::Reject(String::from("Not you again"));
+        }
+        drawing.data[0] = 0;
+        Verdict::Allow
+    }
+}
+
+fn start_server(plugins: Vec<Arc<dyn Plugin>>) -> TestServer {
+    TestServer::with_config(Config { plugins, ..common::config() })
+}
+
+fn connect(server: &TestServer, name: &str) -> Client {
+    let client = Client::connect(server.addr, name).unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+    client
+}
+
+fn next_announcement(client: &mut Client) -> String {
+    match client.next_event().unwrap() {
+        Event::Announcement(text) => text,
+        other => panic!("expected an announcement, got {other:?}")
+    }
+}
+
+fn next_drawing(client: &mut Client) -> TextureData {
+    match client.next_event().unwrap() {
+        Event::Drawing(texture_data) => texture_data,
+        other => panic!("expected a drawing, got {other:?}")
+    }
+}
+
+#[test]
+fn joining_clients_are_welcomed() {
+    let server = start_server(vec![Arc::new(Welcome { message: String::from("hello there") })]);
+    let mut alice = connect(&server, "alice");
+    alice.fetch_history().unwrap();
+    assert_eq!(next_announcement(&mut alice), "hello there");
+}
+
+#[test]
+fn plugins_can_change_and_reject_drawings() {
+    let server = start_server(vec![Arc::new(Moderator)]);
+    let mut alice = connect(&server, "alice");
+    alice.fetch_history().unwrap();
+    let mut spammer = connect(&server, "spammer");
+    spammer.fetch_history().unwrap();
+
+    // Only the author hears about the rejection and nothing is stored
+    spammer.send_drawing(vec![1; CANVAS_SIZE]).unwrap();
+    assert_eq!(next_announcement(&mut spammer), "Not you again");
+    assert!(matches!(spammer.next_event().unwrap(), Event::DrawingRefused(0)));
+
+    alice.send_drawing(vec![1; CANVAS_SIZE]).unwrap();
+    let stored = next_drawing(&mut alice);
+    assert_eq!(stored.id, 1);
+    assert_eq!(stored.data[0], 0);
+    assert_eq!(stored.data[1], 1);
+    assert_eq!(next_drawing(&mut spammer).id, 1);
+}
+
+#[test]
+fn leaving_is_announced_to_the_rest() {
+    let server = start_server(vec![Arc::new(Moderator)]);
+    let mut alice = connect(&server, "alice");
+    alice.fetch_history().unwrap();
+    let mut bob = connect(&server, "bob");
+    bob.fetch_history().unwrap();
+    bob.send_drawing(vec![1; CANVAS_SIZE]).unwrap();
+    next_drawing(&mut alice);
+    next_drawing(&mut bob);
+
+    drop(bob);
+    assert_eq!(next_announcement(&mut alice), "bob left");
+}
+
+#[test]
+fn blank_drawings_are_rejected() {
+    let server = start_server(vec![Arc::new(RejectBlank)]);
+    let mut alice = connect(&server, "alice");
+    alice.fetch_history().unwrap();
+    alice.send_drawing(vec![BACKGROUND; CANVAS_SIZE]).unwrap();
+    next_announcement(&mut alice);
+    assert!(matches!(alice.next_event().unwrap(), Event::DrawingRefused(0)));
+
+    let mut bob = connect(&server, "bob");
+    assert!(bob.fetch_history().unwrap().is_empty());
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use common::{TestServer, TIMEOUT};
+use psrs_client::{Client, Error, Event};
+use psrs_protocol::*;
+use psrs_server::Config;
+
+fn start_server(key: &str, peers: &[&TestServer]) -> TestServer {
+    TestServer::with_config(Config {
+        relay_peers: peers.iter().map(|peer| peer.addr.to_string()).collect(),
+        relay_key: Some(String::from(key)),
+        ..common::config()
+    })
+}
+
+fn join(server: &TestServer, name: &str) -> Client {
+    let mut client = Client::connect(server.addr, name).unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+    client.fetch_history().unwrap();
+    client
+}
+
+fn next_drawing(client: &mut Client) -> TextureData {
+    match client.next_event().unwrap() {
+        Event::Drawing(texture_data) => texture_data,
+        other => panic!("expected a drawing, got {other:?}")
+    }
+}
+
+// Waits a little to make sure nothing else turns up
+fn assert_quiet(client: &mut Client) {
+    client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    match client.next_event() {
+        Err(Error::Io(_)) => {},
+        other => panic!("expected nothing, got {other:?}")
+    }
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+}
+
+fn relayed(id: i32, timestamp: u128) -> TextureData {
+    TextureData { timestamp, id, ..common::drawing("mallory", 1) }
+}
+
+fn read_header(stream: &mut TcpStream) -> InfoData {
+    let mut header = [0; INFO_SIZE];
+    stream.read_exact(&mut header).unwrap();
+    decode_header(&header).unwrap()
+}
+
+#[test]
+fn drawings_are_mirrored_both_ways() {
+    let first = start_server("office", &[]);
+    let second = start_server("office", &[&first]);
+    let mut alice = join(&first, "alice");
+    let mut bob = join(&second, "bob");
+
+    alice.send_drawing(vec![1; CANVAS_SIZE]).unwrap();
+    assert_eq!(display_name(&next_drawing(&mut alice).name), "alice");
+    let mirrored = next_drawing(&mut bob);
+    assert_eq!(display_name(&mirrored.name), "alice");
+    assert_eq!(mirrored.data, vec![1; CANVAS_SIZE]);
+
+    // Replies only point at the drawing they answer on the server they were sent to
+    bob.send_reply(vec![2; CANVAS_SIZE], mirrored.id).unwrap();
+    assert_eq!(next_drawing(&mut bob).reply_to, mirrored.id);
+    let reply = next_drawing(&mut alice);
+    assert_eq!(display_name(&reply.name), "bob");
+    assert_eq!(reply.reply_to, 0);
+
+    // Each server numbers the drawings it stores itself
+    let mut carol = Client::connect(second.addr, "carol").unwrap();
+    let history = carol.fetch_history().unwrap();
+    assert_eq!(history.iter().map(|item| item.id).collect::<Vec<_>>(), [1, 2]);
+}
+
+#[test]
+fn drawings_arrive_once_around_a_loop() {
+    // Three servers all linked to each other, so every drawing has two ways round
+    let first = start_server("office", &[]);
+    let second = start_server("office", &[&first]);
+    let third = start_server("office", &[&first, &second]);
+    let mut clients = [join(&first, "alice"), join(&second, "bob"), join(&third, "carol")];
+
+    clients[0].send_drawing(vec![1; CANVAS_SIZE]).unwrap();
+    clients[2].send_drawing(vec![3; CANVAS_SIZE]).unwrap();
+    for client in &mut clients {
+        let mut names = vec![display_name(&next_drawing(client).name), display_name(&next_drawing(client).name)];
+        names.sort();
+        assert_eq!(names, ["alice", "carol"]);
+    }
+    for client in &mut clients {
+        assert_quiet(client);
+    }
+}
+
+#[test]
+fn servers_with_different_keys_stay_apart() {
+    let first = start_server("one", &[]);
+    let second = start_server("two", &[&first]);
+    let mut alice = join(&first, "alice");
+    let mut bob = join(&second, "bob");
+
+    alice.send_drawing(vec![1; CANVAS_SIZE]).unwrap();
+    next_drawing(&mut alice);
+    assert_quiet(&mut bob);
+}
+
+#[test]
+fn clients_cant_send_relayed_drawings() {
+    let server = start_server("office", &[]);
+    let mut stream = TcpStream::connect(server.addr).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.write_all(&relay_drawing(42, &relayed(1, now_millis()))).unwrap();
+
+    let reply = read_header(&mut stream);
+    assert_eq!(reply.msg, InfoMsg::Error);
+    assert_eq!(reply.number, ProtocolError::UnexpectedMessage as i32);
+
+    let mut bob = Client::connect(server.addr, "bob").unwrap();
+    assert!(bob.fetch_history().unwrap().is_empty());
+}
+
+#[test]
+fn peers_dont_trade_errors_over_a_rejected_drawing() {
+    let server = start_server("office", &[]);
+    let mut alice = join(&server, "alice");
+    // Stands in for a linked server whose clock is an hour ahead
+    let mut peer = TcpStream::connect(server.addr).unwrap();
+    peer.set_read_timeout(Some(TIMEOUT)).unwrap();
+    peer.write_all(&relay_hello(&RelayHello { server_id: 42, key: String::from("office") })).unwrap();
+    let hello = read_header(&mut peer);
+    assert_eq!(hello.msg, InfoMsg::RelayHello);
+    peer.read_exact(&mut vec![0; hello.number as usize]).unwrap();
+
+    // The drawing is dropped without an answer, and an error back from the peer isn't answered either
+    peer.write_all(&relay_drawing(42, &relayed(1, now_millis() + 60 * 60 * 1000))).unwrap();
+    peer.write_all(&ProtocolError::BadTimestamp.reply().to_bytes()).unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let mut byte = [0];
+    assert!(peer.read(&mut byte).is_err());
+    assert_quiet(&mut alice);
+
+    // The link carries on as before
+    peer.write_all(&relay_drawing(42, &relayed(2, now_millis()))).unwrap();
+    assert_eq!(display_name(&next_drawing(&mut alice).name), "mallory");
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use std::net::TcpStream;
+use common::*;
+use psrs_protocol::*;
+use psrs_server::Config;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+// The usual test server, with a WebSocket gateway on an ephemeral port too
+fn start_server() -> TestServer {
+    TestServer::with_config(Config { ws_addr: Some(String::from("127.0.0.1:0")), ..config() })
+}
+
+// A browser client: the same bytes as FakeClient sends, carried in WebSocket messages
+struct WsPeer {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    received: Vec<u8>
+}
+
+impl WsPeer {
+    fn connect(server: &TestServer) -> WsPeer {
+        let (socket, _) = tungstenite::connect(format!("ws://{}/", server.ws_addr.unwrap())).unwrap();
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        }
+        WsPeer { socket, received: Vec::new() }
+    }
+
+    fn send(&mut self, bytes: &[u8]) {
+        self.socket.send(Message::Binary(bytes.to_vec())).unwrap();
+    }
+
+    fn read_exact(&mut self, len: usize) -> Vec<u8> {
+        while self.received.len() < len {
+            match self.socket.read().unwrap() {
+                Message::Binary(data) => self.received.extend(data),
+                other => panic!("unexpected message {other:?}")
+            }
+        }
+        self.received.drain(..len).collect()
+    }
+
+    fn read_header(&mut self) -> InfoData {
+        decode_header(&self.read_exact(INFO_SIZE)).unwrap()
+    }
+
+    fn sync(&mut self) -> Vec<TextureData> {
+        self.send(&header(InfoMsg::RequestHistoryLength, 0));
+        let length = self.read_header();
+        assert_eq!(length.msg, InfoMsg::HistoryLength);
+        self.send(&header(InfoMsg::RequestHistory, 0));
+        let history = bincode::deserialize(&self.read_exact(length.number as usize)).unwrap();
+        self.send(&header(InfoMsg::ConfirmReceivedHistory, 0));
+        history
+    }
+
+    fn read_drawing(&mut self) -> TextureData {
+        let info = self.read_header();
+        assert_eq!(info.msg, InfoMsg::Drawing, "expected a drawing");
+        decode_drawing(&self.read_exact(info.number as usize), now_millis()).unwrap()
+    }
+}
+
+fn name_of(texture_data: &TextureData) -> String {
+    validate_name(&texture_data.name).unwrap()
+}
+
+#[test]
+fn browser_and_desktop_clients_see_each_others_drawings() {
+    let server = start_server();
+    let (mut desktop, history) = FakeClient::join(&server);
+    assert!(history.is_empty());
+    let mut browser = WsPeer::connect(&server);
+    assert!(browser.sync().is_empty());
+
+    browser.send(&drawing_packet(&drawing("browser", 127)));
+    assert_eq!(name_of(&desktop.read_drawing()), "browser");
+    assert_eq!(name_of(&browser.read_drawing()), "browser");
+
+    desktop.send_drawing(&drawing("desktop", 127));
+    assert_eq!(name_of(&browser.read_drawing()), "desktop");
+    assert_eq!(name_of(&desktop.read_drawing()), "desktop");
+}
+
+#[test]
+fn browser_gets_history_from_desktop_clients() {
+    let server = start_server();
+    let (mut desktop, _) = FakeClient::join(&server);
+    desktop.send_drawing(&drawing("desktop", 127));
+    desktop.read_drawing();
+
+    let mut browser = WsPeer::connect(&server);
+    let history = browser.sync();
+    assert_eq!(history.len(), 1);
+    assert_eq!(name_of(&history[0]), "desktop");
+}
+
+#[test]
+fn websocket_messages_are_one_byte_stream() {
+    let server = start_server();
+    let mut browser = WsPeer::connect(&server);
+    browser.sync();
+
+    // Header and payload split over three WebSocket messages
+    let packet = drawing_packet(&drawing("browser", 127));
+    browser.send(&packet[..INFO_SIZE]);
+    browser.send(&packet[INFO_SIZE..1000]);
+    browser.send(&packet[1000..]);
+    assert_eq!(browser.read_drawing().id, 1);
+
+    // Two drawings batched into one
+    let mut batch = drawing_packet(&drawing("browser", 127));
+    batch.extend(drawing_packet(&drawing("browser", 127)));
+    browser.send(&batch);
+    assert_eq!(browser.read_drawing().id, 2);
+    assert_eq!(browser.read_drawing().id, 3);
+}
+
+#[test]
+fn bad_websocket_input_gets_an_error_and_is_disconnected() {
+    let server = start_server();
+    let mut browser = WsPeer::connect(&server);
+    browser.sync();
+
+    let mut unknown = header(InfoMsg::Nothing, 0);
+    unknown[0] = 200;
+    browser.send(&unknown);
+    let reply = browser.read_header();
+    assert_eq!(reply.msg, InfoMsg::Error);
+    assert_eq!(reply.number, ProtocolError::UnknownMessage as i32);
+
+    // The server hangs up after a fatal error
+    let closed = match browser.socket.read() {
+        Ok(Message::Close(_)) => true,
+        Ok(other) => panic!("unexpected message {other:?}"),
+        Err(_) => true
+    };
+    assert!(closed);
+}
@@ -0,0 +1,96 @@
+use std::time::{Duration, Instant};
+
+use crate::fixtures::Fixtures;
+use crate::glyphface::GlyphFace;
+use crate::network::NetworkHandle;
+
+// Ours goes out at most this often while we keep at it
+const SEND_INTERVAL: Duration = Duration::from_secs(2);
+// How long after their last signal someone stops being shown as drawing
+const EXPIRY: Duration = Duration::from_secs(5);
+
+// The "alice is drawing..." line above the canvas, and letting everyone else know when we are
+pub struct Activity {
+    network: NetworkHandle,
+    last_sent: Option<Instant>,
+    // Who else is drawing and when we last heard from them, oldest first
+    others: Vec<(String, Instant)>,
+    vbo: gl::types::GLuint
+}
+
+impl Activity {
+    pub fn new(network: NetworkHandle) -> Activity {
+        Activity {
+            network,
+            last_sent: None,
+            others: Vec::new(),
+            vbo: 0
+        }
+    }
+
+    // For every stroke on the canvas and every key typed onto it
+    pub fn ours(&mut self) {
+        let now = Instant::now();
+        if self.last_sent.is_some_and(|last| now.duration_since(last) < SEND_INTERVAL) {
+            return;
+        }
+        self.last_sent = Some(now);
+        self.network.activity();
+    }
+
+    pub fn heard(&mut self, name: String) {
+        self.others.retain(|(other, _)| *other != name);
+        self.others.push((name, Instant::now()));
+    }
+
+    // Their drawing arrived, so whatever they were busy with is done
+    pub fn sent(&mut self, name: &str) {
+        self.others.retain(|(other, _)| other != name);
+    }
+
+    fn label(&mut self) -> Option<String> {
+        self.others.retain(|(_, heard)| heard.elapsed() < EXPIRY);
+        match self.others.as_slice() {
+            [] => None,
+            [(name, _)] => Some(format!("{name} is drawing...")),
+            [(first, _), (second, _)] => Some(format!("{first} and {second} are drawing...")),
+            more => Some(format!("{} people are drawing...", more.len()))
+        }
+    }
+
+    // Glyph quads like Fixtures::draw_tooltip, sitting on the top edge of the canvas
+    pub fn draw(&mut self, windowwidth: i32, windowheight: i32, shader: gl::types::GLuint, fixtures: &Fixtures) {
+        let Some(label) = self.label() else {
+            return;
+        };
+        let gwidth = 32.0 / windowwidth as f32;
+        let gheight = 32.0 / windowheight as f32;
+        let left = -1.0 + gwidth / 2.0;
+        let y = 0.0;
+
+        let mut geometry: Vec<f32> = Vec::new();
+        let mut g = GlyphFace::new(0);
+        for (l, c) in label.chars().enumerate() {
+            g.set_char(if c.is_ascii_graphic() || c == ' ' { c as u8 } else { b'?' });
+            let x = left + l as f32 * gwidth;
+            geometry.extend_from_slice(&[
+                x,          y,            g.blx, g.bly,  -1.0,
+                x,          y + gheight,  g.tlx, g.tly,  -1.0,
+                x + gwidth, y + gheight,  g.trx, g.tr_y, -1.0,
+
+                x + gwidth, y + gheight,  g.trx, g.tr_y, -1.0,
+                x + gwidth, y,            g.brx, g.bry,  -1.0,
+                x,          y,            g.blx, g.bly,  -1.0,
+            ]);
+        }
+        unsafe {
+            gl::UseProgram(shader);
+            gl::BindVertexArray(fixtures.vao);
+            gl::DeleteBuffers(1, &self.vbo);
+            gl::GenBuffers(1, &mut self.vbo);
+            fixtures.bind_geometry(self.vbo, true, shader, &geometry);
+            gl::BindTexture(gl::TEXTURE_2D, fixtures.texture);
+            gl::DrawArrays(gl::TRIANGLES, 0, (geometry.len() / 5) as i32);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+const USAGE: &str = "Usage: pictosendrs [--name <name>] [--server <address:port>]
+
+Given a server (and a name, unless one is remembered from last time) the client
+connects straight away instead of waiting on the connection screen. Use
+\"--server lan\" to share drawings on the local network without a server.";
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub name: Option<String>,
+    pub server: Option<String>
+}
+
+// Anything we don't understand prints the usage and exits, as does --help
+pub fn parse() -> Args {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match parse_from(&args) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{USAGE}");
+            std::process::exit(0);
+        },
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    }
+}
+
+// None when help was asked for
+fn parse_from(args: &[String]) -> Result<Option<Args>, String> {
+    let mut parsed = Args::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().cloned().ok_or_else(|| format!("{flag} needs a value"));
+        match arg.as_str() {
+            "--name" => parsed.name = Some(value("--name")?),
+            "--server" => parsed.server = Some(value("--server")?),
+            "-h" | "--help" => return Ok(None),
+            other => return Err(format!("unknown argument {other}"))
+        }
+    }
+    Ok(Some(parsed))
+}
@@ -0,0 +1,74 @@
+use psrs_protocol::canvas::{draw_stroke, Stroke, BACKGROUND};
+use psrs_protocol::CANVAS_SIZE;
+
+use crate::network::NetworkHandle;
+
+// Our copy of the server's shared canvas. While it's on, the canvas shows this
+// instead of our own drawing, and strokes go to everyone else as they're drawn.
+pub struct SharedCanvas {
+    network: NetworkHandle,
+    pub on: bool,
+    pub data: Vec<u8>,
+    // The last stroke drawn, so a pen held still doesn't send the same dot every frame
+    last: Option<Stroke>
+}
+
+impl SharedCanvas {
+    pub fn new(network: NetworkHandle) -> SharedCanvas {
+        SharedCanvas {
+            network,
+            on: false,
+            data: vec![BACKGROUND; CANVAS_SIZE],
+            last: None
+        }
+    }
+
+    // Blank until the server sends us what's on it
+    pub fn toggle(&mut self) {
+        self.on = !self.on;
+        self.data.fill(BACKGROUND);
+        if self.on {
+            self.network.join_board();
+        } else {
+            self.network.leave_board();
+        }
+    }
+
+    pub fn draw(&mut self, stroke: Stroke) {
+        if stroke.from == stroke.to && self.last == Some(stroke) {
+            return;
+        }
+        draw_stroke(&mut self.data, &stroke);
+        self.network.stroke(stroke);
+        self.last = Some(stroke);
+    }
+
+    pub fn clear(&mut self) {
+        self.data.fill(BACKGROUND);
+        self.network.clear_board();
+        self.last = None;
+    }
+
+    // Left as it is until the server takes it and says so with NetEvent::BoardCleared
+    pub fn post(&self) {
+        self.network.post_board();
+    }
+
+    pub fn received(&mut self, data: Vec<u8>) {
+        if self.on && data.len() == CANVAS_SIZE {
+            self.data = data;
+            self.last = None;
+        }
+    }
+
+    pub fn heard(&mut self, stroke: &Stroke) {
+        if self.on {
+            draw_stroke(&mut self.data, stroke);
+        }
+    }
+
+    pub fn cleared(&mut self) {
+        self.data.fill(BACKGROUND);
+        self.last = None;
+    }
+}
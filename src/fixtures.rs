@@ -140,7 +140,7 @@ impl Fixtures {
         self.data = data;
     }
 
-    fn bind_geometry(&self, vbo: gl::types::GLuint, upload: bool, shader: gl::types::GLuint, data: &Vec<f32>) {
+    pub fn bind_geometry(&self, vbo: gl::types::GLuint, upload: bool, shader: gl::types::GLuint, data: &Vec<f32>) {
 
         unsafe {
             gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
@@ -234,7 +234,7 @@ fn compile_shader(path: &str, shader_type: gl::types::GLenum) -> gl::types::GLui
         if success != gl::TRUE as gl::types::GLint {
             let mut log = vec![0; 512];
             gl::GetShaderInfoLog(shader, 512, ptr::null_mut(), log.as_mut_ptr() as *mut gl::types::GLchar);
-            println!("ERROR::SHADER::COMPILATION_FAILED\n{}", str::from_utf8(&log).unwrap());
+            tracing::error!("Shader compilation failed\n{}", String::from_utf8_lossy(&log));
         }
     }
 
@@ -253,7 +253,7 @@ fn link_shader_program(vertex_shader: gl::types::GLuint, fragment_shader: gl::ty
         if success != gl::TRUE as gl::types::GLint {
             let mut log = vec![0; 512];
             gl::GetProgramInfoLog(program, 512, ptr::null_mut(), log.as_mut_ptr() as *mut gl::types::GLchar);
-            println!("ERROR::PROGRAM::LINKING_FAILED\n{}", str::from_utf8(&log).unwrap());
+            tracing::error!("Shader program linking failed\n{}", String::from_utf8_lossy(&log));
         }
     }
 
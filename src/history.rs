@@ -1,6 +1,16 @@
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
+use psrs_protocol::{Reaction, ReactionCounts, TextureData};
 use regex::Regex;
-use crate::{glyphface::GlyphFace, TextureData};
+use crate::glyphface::GlyphFace;
+use crate::outbox::same_drawing;
+
+// The most the server keeps, so the most worth showing
+const MAX_HISTORY: usize = 56;
+// How big the parent's thumbnail above a reply is, next to a full drawing
+const THUMBNAIL_SCALE: f32 = 0.3;
+// Shown for a reply whose parent has been deleted or scrolled out of the history
+static MISSING_PARENT: [u8; 200 * 200] = [127; 200 * 200];
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ChatHistory {
@@ -14,7 +24,11 @@ pub struct ChatHistory {
     pub name_starts: Vec<f32>,
     pub name_geometry: Vec<f32>,
     pub name_vbo: gl::types::GLuint,
-    pub name_dirty: bool
+    pub name_dirty: bool,
+    // Which items are replies, in the order their thumbnails come after the drawings in display_data
+    pub thumbnails: Vec<usize>,
+    // Reaction totals by message id
+    pub reactions: HashMap<i32, ReactionCounts>
 }
 
 impl ChatHistory {
@@ -44,8 +58,73 @@ impl ChatHistory {
             name_starts: Vec::new(),
             name_geometry: Vec::new(),
             name_vbo: 0,
-            name_dirty: false
+            name_dirty: false,
+            thumbnails: Vec::new(),
+            reactions: HashMap::new()
+        }
+    }
+
+    // A drawing that arrived. Ours are already shown as pending until they come back.
+    pub fn add(&mut self, texture_data: TextureData) {
+        self.history.retain(|item| item.id != 0 || !same_drawing(item, &texture_data));
+        self.history.push(texture_data);
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+        self.history.sort_by_key(|item| item.timestamp);
+        self.dirty = true;
+    }
+
+    pub fn replace(&mut self, mut items: Vec<TextureData>) {
+        items.sort_by_key(|item| item.timestamp);
+        self.history = items;
+        // The server sends the totals again straight after the history
+        self.reactions.clear();
+        self.dirty = true;
+    }
+
+    pub fn remove(&mut self, message_id: i32) {
+        self.history.retain(|item| item.id != message_id);
+        self.reactions.remove(&message_id);
+        self.dirty = true;
+    }
+
+    // Only the counters change, so just the text is rebuilt
+    pub fn set_reactions(&mut self, counts: ReactionCounts) {
+        if counts.counts.iter().all(|count| *count == 0) {
+            self.reactions.remove(&counts.message_id);
+        } else {
+            self.reactions.insert(counts.message_id, counts);
+        }
+        self.name_dirty = true;
+    }
+
+    // Ours that was shown as pending but won't be coming back from the server
+    pub fn withdraw(&mut self, texture_data: &TextureData) {
+        self.history.retain(|item| item.id != 0 || !same_drawing(item, texture_data));
+        self.dirty = true;
+    }
+
+    pub fn find(&self, message_id: i32) -> Option<&TextureData> {
+        self.history.iter().find(|item| item.id == message_id)
+    }
+
+    // Index of the history item under the given window pixel, matching the layout built in draw()
+    pub fn item_at(&self, mousex: i32, mousey: i32, windowwidth: i32, windowheight: i32) -> Option<usize> {
+        let wid = 250.0 / windowwidth as f32;
+        let hei = 500.0 / windowheight as f32;
+
+        let x = (mousex as f32 / windowwidth as f32) * 2.0 - 1.0;
+        let y = 1.0 - (mousey as f32 / windowheight as f32) * 2.0 - self.scroll_offset;
+
+        for i in 0..self.history.len().min(self.name_starts.len() / 2) {
+            let left = self.name_starts[i * 2];
+            let bottom = self.name_starts[i * 2 + 1];
+            if x > left && x < left + wid * 2.0 && y > bottom && y < bottom + hei {
+                return Some(i);
+            }
         }
+        None
     }
 
     fn bind_scroll_geometry(&self, vbo: gl::types::GLuint, upload: bool, shader: gl::types::GLuint, data: &Vec<f32>) {
@@ -108,6 +187,7 @@ impl ChatHistory {
                 self.name_dirty = true;
                 self.display_data.clear();
                 self.name_starts.clear();
+                self.thumbnails.clear();
                 gl::DeleteBuffers(1, &self.vbo);
                 gl::GenBuffers(1, &mut self.vbo);
 
@@ -119,24 +199,52 @@ impl ChatHistory {
                 let bytes = myname.as_bytes();
                 let mut fixed_size_text = [0u8; 24];
                 fixed_size_text[..bytes.len()].copy_from_slice(bytes);
-                
+
+                // Replies get extra room above them for their parent's thumbnail,
+                // so how high each item sits is added up from the newest one
+                let thumb_gap = (space - hei) / 2.0;
+                let mut rises = vec![0.0; self.history.len()];
+                for i in (1..self.history.len()).rev() {
+                    let thumb_space = if self.history[i].reply_to != 0 { hei * THUMBNAIL_SCALE + thumb_gap } else { 0.0 };
+                    rises[i - 1] = rises[i] + space + thumb_space;
+                }
+
                 for i in 0..self.history.len() {
                     for v in 0..6 {
                         let vstart = v*4;
                         self.display_data.extend_from_slice(&[
                             (if self.history[i].name == fixed_size_text { 0.3 } else { -0.3 } ) + QUAD_VERTICES[vstart+0] * wid,
-                            (QUAD_VERTICES[vstart+1] * hei) + 0.8 + ((self.history.len() - 1 - i) as f32 * space),
+                            (QUAD_VERTICES[vstart+1] * hei) + 0.8 + rises[i],
                             QUAD_VERTICES[vstart+2], 
                             QUAD_VERTICES[vstart+3],
                         ]);
                         if v == 0 {
                             self.name_starts.extend_from_slice(&[
                                 (if self.history[i].name == fixed_size_text { 0.3 } else { -0.3 } ) + QUAD_VERTICES[vstart+0] * wid,
-                                (QUAD_VERTICES[vstart+1] * hei) + 0.8 + ((self.history.len() - 1 - i) as f32 * space),
+                                (QUAD_VERTICES[vstart+1] * hei) + 0.8 + rises[i],
                             ]);
                         }
                     }
                 }
+
+                // Thumbnails sit just above their reply, lined up with its left edge
+                for (i, item) in self.history.iter().enumerate() {
+                    if item.reply_to == 0 {
+                        continue;
+                    }
+                    let left = self.name_starts[i * 2];
+                    let top = 0.8 + rises[i] + thumb_gap + hei * THUMBNAIL_SCALE;
+                    for v in 0..6 {
+                        let vstart = v*4;
+                        self.display_data.extend_from_slice(&[
+                            left + (QUAD_VERTICES[vstart] + 1.0) * wid * THUMBNAIL_SCALE,
+                            (QUAD_VERTICES[vstart+1] * hei * THUMBNAIL_SCALE) + top,
+                            QUAD_VERTICES[vstart+2],
+                            QUAD_VERTICES[vstart+3],
+                        ]);
+                    }
+                    self.thumbnails.push(i);
+                }
                 self.dirty = false;
                 self.bind_scroll_geometry(self.vbo, true, shader, &self.display_data);
             } else {
@@ -162,6 +270,27 @@ impl ChatHistory {
                 );
                 gl::DrawArrays(gl::TRIANGLES, bs as i32, 6);
             }
+
+            for (n, &i) in self.thumbnails.iter().enumerate() {
+                let bs = (self.history.len() + n) * 6;
+                let parent = match self.find(self.history[i].reply_to) {
+                    Some(parent) => &parent.data[..],
+                    None => &MISSING_PARENT[..]
+                };
+                gl::BindTexture(gl::TEXTURE_2D, self.texture);
+                gl::TexSubImage2D(
+                    gl::TEXTURE_2D,
+                    0,
+                    0,
+                    0,
+                    200,
+                    200,
+                    gl::RED,
+                    gl::UNSIGNED_BYTE,
+                    parent.as_ptr() as *const gl::types::GLvoid
+                );
+                gl::DrawArrays(gl::TRIANGLES, bs as i32, 6);
+            }
         }
         return return_value;
     }
@@ -190,6 +319,10 @@ impl ChatHistory {
                 let re = Regex::new(r"[ -~]").unwrap();
                 // Filter the string to only include characters that match the regex
                 namestring = namestring.chars().filter(|c| re.is_match(&c.to_string())).collect();
+                // Ours, still waiting for the server to take it (see Outbox)
+                if self.history[i].id == 0 {
+                    namestring.push_str(" (sending)");
+                }
 
                 let letters_count = namestring.chars().count();
 
@@ -199,7 +332,7 @@ impl ChatHistory {
 
                 let mut g = GlyphFace::new(0);
                 for l in 0..letters_count {
-                    g.set_char(name[l]);
+                    g.set_char(namestring.as_bytes()[l]);
                     self.name_geometry.extend_from_slice(&[
                         l as f32 * gwidth + namex,          namey,            g.blx,g.bly,
                         l as f32 * gwidth + namex,          namey + gheight,  g.tlx,g.tly,
@@ -210,6 +343,40 @@ impl ChatHistory {
                         l as f32 * gwidth + namex,          namey,            g.blx,g.bly,
                     ]);
                 }
+
+                // A counter per stamp it has, like "*3", stacked down the side facing the
+                // middle: right of others' drawings, and left of ours since those sit
+                // right of the middle (see draw) and there's no room past them
+                let Some(counts) = self.reactions.get(&self.history[i].id) else {
+                    continue;
+                };
+                let wid = 250.0 / windowwidth as f32;
+                let ours = namex + wid > 0.0;
+                let mut county = self.name_starts[nbs+1] + 500.0 / windowheight as f32;
+                for (reaction, count) in Reaction::ALL.iter().zip(counts.counts) {
+                    if count == 0 {
+                        continue;
+                    }
+                    county -= gheight;
+                    let counter = format!("{}{}", reaction.glyph() as char, count);
+                    let countx = if ours {
+                        namex - gwidth / 2.0 - counter.len() as f32 * gwidth
+                    } else {
+                        namex + wid * 2.0 + gwidth / 2.0
+                    };
+                    for (l, letter) in counter.bytes().enumerate() {
+                        g.set_char(letter);
+                        self.name_geometry.extend_from_slice(&[
+                            l as f32 * gwidth + countx,          county,            g.blx,g.bly,
+                            l as f32 * gwidth + countx,          county + gheight,  g.tlx,g.tly,
+                            l as f32 * gwidth + countx + gwidth, county + gheight,  g.trx, g.tr_y,
+
+                            l as f32 * gwidth + countx + gwidth, county + gheight,  g.trx, g.tr_y,
+                            l as f32 * gwidth + countx + gwidth, county,            g.brx, g.bry,
+                            l as f32 * gwidth + countx,          county,            g.blx,g.bly,
+                        ]);
+                    }
+                }
             }
             unsafe {
                 self.bind_scroll_geometry(vbo, true, shader, &self.name_geometry);
@@ -0,0 +1,361 @@
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::Duration;
+
+use glfw::{Action, Context, Key, WindowEvent};
+use psrs_client::lan::{LanPeer, LAN_GROUP};
+use psrs_client::DiscoveredServer;
+use psrs_protocol::discovery::DISCOVERY_PORT;
+use psrs_protocol::{pad_name, NAME_SIZE};
+use tracing::{debug, info};
+
+use crate::args::Args;
+use crate::fixtures::Fixtures;
+use crate::glyphface::GlyphFace;
+use crate::profile::Profile;
+
+// Typed instead of a server address to go serverless, see psrs_client::lan
+pub const LAN_MODE: &str = "lan";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_ADDRESS_LEN: usize = 64;
+// Glyphs are drawn this many pixels square, whatever the window size
+const GLYPH_SIZE: f32 = 24.0;
+
+pub enum Connection {
+    Server(TcpStream),
+    Lan(LanPeer)
+}
+
+pub struct Login {
+    pub name: String,
+    // As typed or picked, for remembering
+    pub server: String,
+    pub connection: Connection
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Name,
+    Server
+}
+
+// Something on the screen that reacts to the mouse
+#[derive(Clone, Copy, PartialEq)]
+enum Target {
+    Field(Field),
+    Connect,
+    // Index into the profile's recently used servers
+    Recent(usize),
+    // Index into the discovered servers; one past the end is LAN mode
+    Server(usize)
+}
+
+struct Row {
+    text: String,
+    target: Option<Target>
+}
+
+// Asks for a name and a server inside the window, before anything else is shown.
+// Both start out as given on the command line or as last time. If the command
+// line named a server and we know a name, we connect without waiting for a click.
+// Connecting happens on another thread so the window stays responsive, and
+// anything that goes wrong is shown as a message to fix and retry rather than a panic.
+// Returns None if the window is closed first.
+pub fn run(glfw: &mut glfw::Glfw, window: &mut glfw::PWindow, events: &glfw::GlfwReceiver<(f64, WindowEvent)>, fixtures: &Fixtures, shader: gl::types::GLuint, args: &Args, profile: &Profile) -> Option<Login> {
+    let mut name = args.name.clone().unwrap_or_else(|| profile.name.clone());
+    let mut server = args.server.clone().or_else(|| profile.servers.first().cloned()).unwrap_or_default();
+    let mut focus = if name.is_empty() { Field::Name } else { Field::Server };
+    let mut message = String::from("Pick a name, then a server to connect to.");
+    let mut submit = args.server.is_some() && !name.is_empty();
+    let mut servers: Vec<DiscoveredServer> = Vec::new();
+    let mut attempt: Option<Receiver<Result<Connection, String>>> = None;
+
+    let (found, discovery) = mpsc::channel();
+    std::thread::spawn(move || {
+        let servers = psrs_client::discover(DISCOVERY_PORT, Duration::from_secs(1)).unwrap_or_else(|e| {
+            debug!(error = %e, "Discovery failed");
+            Vec::new()
+        });
+        let _ = found.send(servers);
+    });
+
+    let (mut width, mut height) = window.get_framebuffer_size();
+    let mut pressed: Option<Target> = None;
+    let mut vbo: gl::types::GLuint = 0;
+
+    while !window.should_close() {
+        glfw.poll_events();
+
+        if let Ok(list) = discovery.try_recv() {
+            servers = list;
+        }
+
+        if let Some(receiver) = &attempt {
+            match receiver.try_recv() {
+                Ok(Ok(connection)) => {
+                    unsafe {
+                        gl::DeleteBuffers(1, &vbo);
+                    }
+                    return Some(Login { name: name.trim().to_string(), server, connection });
+                },
+                Ok(Err(e)) => {
+                    info!(error = %e, "Failed to connect");
+                    message = e;
+                    attempt = None;
+                },
+                Err(TryRecvError::Empty) => {},
+                Err(TryRecvError::Disconnected) => {
+                    message = String::from("Connecting failed unexpectedly, please try again.");
+                    attempt = None;
+                }
+            }
+        }
+
+        let rows = layout(&name, &server, focus, &message, &profile.servers, &servers, attempt.is_some());
+        let (_, mousey) = window.get_cursor_pos();
+        let hovered = row_at(&rows, mousey as f32, height as f32).and_then(|index| rows[index].target);
+
+        for (_, event) in glfw::flush_messages(events) {
+            match event {
+                WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                    window.set_should_close(true)
+                },
+                WindowEvent::Key(Key::Tab, _, Action::Press, _) => {
+                    focus = if focus == Field::Name { Field::Server } else { Field::Name };
+                },
+                WindowEvent::Key(Key::Enter, _, Action::Press, _) => {
+                    if focus == Field::Name && server.is_empty() {
+                        focus = Field::Server;
+                    } else {
+                        submit = true;
+                    }
+                },
+                WindowEvent::Key(Key::Backspace, _, Action::Press | Action::Repeat, _) => {
+                    match focus {
+                        Field::Name => name.pop(),
+                        Field::Server => server.pop()
+                    };
+                },
+                // Only what the glyph sheet can show
+                WindowEvent::Char(code) if code.is_ascii_graphic() || code == ' ' => {
+                    match focus {
+                        Field::Name if name.len() < NAME_SIZE => name.push(code),
+                        Field::Server if server.len() < MAX_ADDRESS_LEN && code != ' ' => server.push(code),
+                        _ => {}
+                    }
+                },
+                WindowEvent::MouseButton(glfw::MouseButtonLeft, Action::Press, _) => {
+                    pressed = hovered;
+                },
+                WindowEvent::MouseButton(glfw::MouseButtonLeft, Action::Release, _) => {
+                    match pressed.take().filter(|target| Some(*target) == hovered) {
+                        Some(Target::Field(field)) => focus = field,
+                        Some(Target::Connect) => submit = true,
+                        Some(Target::Recent(index)) => {
+                            server = profile.servers[index].clone();
+                            focus = Field::Server;
+                        },
+                        Some(Target::Server(index)) => {
+                            server = match servers.get(index) {
+                                Some(found) => found.addr.to_string(),
+                                None => String::from(LAN_MODE)
+                            };
+                            focus = Field::Server;
+                        },
+                        None => {}
+                    }
+                },
+                WindowEvent::FramebufferSize(wid, hei) => {
+                    width = wid;
+                    height = hei;
+                    unsafe {
+                        gl::Viewport(0, 0, wid, hei);
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        if submit && attempt.is_none() {
+            match check(&name, &server) {
+                Ok(()) => {
+                    info!(server = %server, "Trying to connect");
+                    message = format!("Connecting to {server}...");
+                    let (done, receiver) = mpsc::channel();
+                    let (name, server) = (name.trim().to_string(), server.clone());
+                    std::thread::spawn(move || {
+                        let _ = done.send(connect(&server, &name));
+                    });
+                    attempt = Some(receiver);
+                },
+                Err(e) => message = e
+            }
+        }
+        submit = false;
+
+        let rows = layout(&name, &server, focus, &message, &profile.servers, &servers, attempt.is_some());
+        let geometry = geometry(&rows, width as f32, height as f32);
+        let moused_over = row_at(&rows, mousey as f32, height as f32)
+            .filter(|index| rows[*index].target.is_some())
+            .map_or(0.0, |index| index as f32 + 1.0);
+        let clicked_on = match pressed {
+            Some(target) => rows.iter().position(|row| row.target == Some(target)).map_or(0.0, |index| index as f32 + 1.0),
+            None => 0.0
+        };
+
+        unsafe {
+            gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+            gl::BindVertexArray(fixtures.vao);
+            gl::DeleteBuffers(1, &vbo);
+            gl::GenBuffers(1, &mut vbo);
+            fixtures.bind_geometry(vbo, true, shader, &geometry);
+            gl::BindTexture(gl::TEXTURE_2D, fixtures.texture);
+            gl::UseProgram(shader);
+            let moe_location = gl::GetUniformLocation(shader, b"mousedOverElement\0".as_ptr() as *const i8);
+            gl::Uniform1f(moe_location, moused_over);
+            let coe_location = gl::GetUniformLocation(shader, b"clickedOnElement\0".as_ptr() as *const i8);
+            gl::Uniform1f(coe_location, clicked_on);
+            gl::DrawArrays(gl::TRIANGLES, 0, (geometry.len() / 5) as i32);
+        }
+        window.swap_buffers();
+    }
+    None
+}
+
+// Everything on the screen, one line of text per row from the top
+fn layout(name: &str, server: &str, focus: Field, message: &str, recent: &[String], servers: &[DiscoveredServer], connecting: bool) -> Vec<Row> {
+    let cursor = |field: Field| if focus == field { "_" } else { "" };
+    let mut rows = vec![
+        Row { text: String::from("PictoSend RS"), target: None },
+        Row { text: String::new(), target: None },
+        Row { text: String::from("Name:"), target: None },
+        Row { text: format!(" {name}{}", cursor(Field::Name)), target: Some(Target::Field(Field::Name)) },
+        Row { text: String::from("Server (address:port):"), target: None },
+        Row { text: format!(" {server}{}", cursor(Field::Server)), target: Some(Target::Field(Field::Server)) },
+        Row { text: String::new(), target: None },
+        Row { text: String::from(if connecting { "[ Connecting ]" } else { "[ Connect ]" }), target: Some(Target::Connect) },
+        Row { text: String::new(), target: None }
+    ];
+    for line in wrap(message, 32) {
+        rows.push(Row { text: line, target: None });
+    }
+    if !recent.is_empty() {
+        rows.push(Row { text: String::new(), target: None });
+        rows.push(Row { text: String::from("Recently used:"), target: None });
+        for (index, address) in recent.iter().enumerate() {
+            rows.push(Row { text: format!(" {address}"), target: Some(Target::Recent(index)) });
+        }
+    }
+    rows.push(Row { text: String::new(), target: None });
+    rows.push(Row { text: String::from("On this network:"), target: None });
+    for (index, found) in servers.iter().enumerate() {
+        rows.push(Row {
+            text: format!(" {} ({} online)", found.info.name, found.info.clients),
+            target: Some(Target::Server(index))
+        });
+    }
+    rows.push(Row {
+        text: format!(" No server ({LAN_MODE})"),
+        target: Some(Target::Server(servers.len()))
+    });
+    rows
+}
+
+fn wrap(text: &str, columns: usize) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    for word in text.split_whitespace() {
+        let line = lines.last_mut().unwrap();
+        if !line.is_empty() && line.len() + 1 + word.len() > columns {
+            lines.push(word.to_string());
+        } else {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+    }
+    lines
+}
+
+// Rows are GLYPH_SIZE pixels tall with a little padding, starting a row down from the top
+fn row_at(rows: &[Row], mousey: f32, windowheight: f32) -> Option<usize> {
+    let row_height = GLYPH_SIZE * 1.25;
+    let index = (mousey / row_height) as usize;
+    if mousey < 0.0 || mousey > windowheight || index == 0 {
+        return None;
+    }
+    (index - 1 < rows.len()).then(|| index - 1)
+}
+
+// Glyph quads laid out like Fixtures::draw_tooltip, with each row's index + 1 as
+// its element id so the menu shader highlights whatever the mouse is over
+fn geometry(rows: &[Row], windowwidth: f32, windowheight: f32) -> Vec<f32> {
+    let gwidth = GLYPH_SIZE * 2.0 / windowwidth;
+    let gheight = GLYPH_SIZE * 2.0 / windowheight;
+    let row_height = gheight * 1.25;
+    let left = -1.0 + gwidth;
+
+    let mut data = Vec::new();
+    let mut g = GlyphFace::new(0);
+    for (index, row) in rows.iter().enumerate() {
+        let id = if row.target.is_some() { index as f32 + 1.0 } else { -1.0 };
+        let y = 1.0 - row_height * (index + 2) as f32;
+        for (l, c) in row.text.chars().enumerate() {
+            g.set_char(if c.is_ascii_graphic() || c == ' ' { c as u8 } else { b'?' });
+            let x = left + l as f32 * gwidth;
+            data.extend_from_slice(&[
+                x,          y,            g.blx, g.bly,  id,
+                x,          y + gheight,  g.tlx, g.tly,  id,
+                x + gwidth, y + gheight,  g.trx, g.tr_y, id,
+
+                x + gwidth, y + gheight,  g.trx, g.tr_y, id,
+                x + gwidth, y,            g.brx, g.bry,  id,
+                x,          y,            g.blx, g.bly,  id,
+            ]);
+        }
+    }
+    data
+}
+
+// Problems we can point out before trying to connect
+fn check(name: &str, server: &str) -> Result<(), String> {
+    pad_name(name.trim()).map_err(|e| format!("That name won't do: {e}."))?;
+    if server.is_empty() {
+        return Err(String::from("Type a server address, or pick one from the list below."));
+    }
+    Ok(())
+}
+
+fn connect(server: &str, name: &str) -> Result<Connection, String> {
+    if server == LAN_MODE {
+        return LanPeer::join(LAN_GROUP, name)
+            .map(Connection::Lan)
+            .map_err(|e| format!("Couldn't join the other clients on this network: {e}"));
+    }
+
+    let addrs: Vec<SocketAddr> = match server.to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(e) if e.kind() == ErrorKind::InvalidInput => {
+            return Err(format!("\"{server}\" isn't an address. Type it as host:port, like localhost:6969."));
+        },
+        Err(_) => return Err(format!("Couldn't find {server}. Check the host name is spelled right."))
+    };
+
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(Connection::Server(stream)),
+            Err(e) => last_error = Some(e)
+        }
+    }
+    Err(match last_error {
+        Some(e) if e.kind() == ErrorKind::ConnectionRefused => format!("Nothing is answering at {server}. Is the server running?"),
+        Some(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {
+            format!("{server} didn't answer within {} seconds.", CONNECT_TIMEOUT.as_secs())
+        },
+        Some(e) => format!("Couldn't reach {server}: {e}"),
+        None => format!("Couldn't find {server}. Check the host name is spelled right.")
+    })
+}
@@ -13,24 +13,34 @@ use fixtures::{Fixture, Fixtures};
 mod textureface;
 
 mod network;
-use network::*;
+use network::{NetEvent, Network};
+
+mod login;
+
+mod args;
+mod profile;
+use profile::Profile;
+
+mod outbox;
+use outbox::Outbox;
+
+mod activity;
+use activity::Activity;
+
+mod board;
+use board::SharedCanvas;
+use psrs_protocol::canvas::{draw_stroke, Stroke};
+use psrs_protocol::logging;
+use psrs_protocol::{Reaction, TextureData};
+use tracing::debug;
 
-use std::net::{Shutdown, TcpStream};
 use std::sync::{Arc, Mutex};
-use std::thread::JoinHandle;
-use serde::{Serialize, Deserialize};
-use bincode::serialized_size;
-use std::io::{Read, Write};
-use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
+use std::time::{SystemTime, UNIX_EPOCH, Instant};
 use crate::typer::Typer;
 use crate::winflash::flash_window;
-use std::sync::atomic::{AtomicBool, Ordering};
-
-use lerp::Lerp;
 
 mod history;
 mod glyphface;
-use std::io;
 
 mod typer;
 
@@ -44,67 +54,23 @@ struct MousePos {
     button: glfw::MouseButton
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct TextureData {
-    name: [u8; 24],
-    data: Vec<u8>,
-    request_history: bool,
-    request_history_length: bool,
-    history_length: i32,
-    confirm_history: bool,
-    timestamp: u128
-}
-
-impl TextureData {
-    fn new(myname: &String) -> Self {
-        let bytes = myname.as_bytes();
-        let mut fixed_size_text = [0u8; 24];
-        fixed_size_text[..bytes.len()].copy_from_slice(bytes);
-        let now = SystemTime::now();
-
-        TextureData {
-            name: fixed_size_text,
-            data: [127; 200 * 200].to_vec(),
-            request_history: false,
-            request_history_length: false,
-            history_length: 0,
-            confirm_history: false,
-            timestamp: now.duration_since(UNIX_EPOCH).unwrap().as_millis()
-        }
-    }
-
-    fn draw(&mut self, mouse: &MousePos, pen: &PenState, width: i32, height: i32, value: u8) {
-        let adjusted_m_y = (mouse.y - (height / 2)).max(0);
-
-        let max = self.data.len() - 1;
-
-        let lastadjusted_m_y = (mouse.lasty - (height / 2)).max(0);
- 
-        for i in 0..15 {
-
-            let t: f32 = i as f32 / 15.0;
-
-            let mut m_x_dist = (mouse.x as f32 / width as f32).clamp(0.0, 1.0);
-            let mut m_y_dist = (adjusted_m_y as f32 / (height / 2) as f32).clamp(0.0, 1.0);
-
-            let lastm_x_dist = (mouse.lastx as f32 / width as f32).clamp(0.0, 1.0);
-            let lastm_y_dist = (lastadjusted_m_y as f32 / (height / 2) as f32).clamp(0.0, 1.0);
-
-            m_x_dist = lastm_x_dist.lerp(m_x_dist, t);
-            m_y_dist = lastm_y_dist.lerp(m_y_dist, t);
-
-            let d_x = (m_x_dist * 200.0) as i32;
-            let d_y = (m_y_dist * 200.0) as i32;
-
-            let d_center = (d_y * 200 + d_x).clamp(0, max as i32);
-
-            for o in pen.pentype.get_spots() {
-                let d_index = d_center + o.x as i32 + (o.y as i32 * 200);
-                self.data[d_index.clamp(0, max as i32) as usize] = value;
-            }
-        }
-            
-
+// An empty canvas of ours; the timestamp is set again when it is sent
+fn blank_drawing(myname: &String) -> TextureData {
+    let bytes = myname.as_bytes();
+    let mut fixed_size_text = [0u8; 24];
+    fixed_size_text[..bytes.len()].copy_from_slice(bytes);
+    let now = SystemTime::now();
+
+    TextureData {
+        name: fixed_size_text,
+        data: [127; 200 * 200].to_vec(),
+        request_history: false,
+        request_history_length: false,
+        history_length: 0,
+        confirm_history: false,
+        timestamp: now.duration_since(UNIX_EPOCH).unwrap().as_millis(),
+        id: 0,
+        reply_to: 0
     }
 }
 
@@ -121,6 +87,22 @@ impl MousePos {
         self.x = xpos as i32;
         self.y = ypos as i32;
     }
+
+    // Where the pen went since the last frame, in canvas pixels
+    fn stroke(&self, pen: &PenState, width: i32, height: i32, value: u8) -> Stroke {
+        let canvas_pos = |x: i32, y: i32| {
+            let adjusted_m_y = (y - (height / 2)).max(0);
+            let m_x_dist = (x as f32 / width as f32).clamp(0.0, 1.0);
+            let m_y_dist = (adjusted_m_y as f32 / (height / 2) as f32).clamp(0.0, 1.0);
+            ((m_x_dist * 200.0) as u8, (m_y_dist * 200.0) as u8)
+        };
+        Stroke {
+            from: canvas_pos(self.lastx, self.lasty),
+            to: canvas_pos(self.x, self.y),
+            pen: pen.pentype.pen(),
+            value
+        }
+    }
 }
 
 struct CameraStuff {
@@ -185,14 +167,21 @@ fn glfw_mouse_pos_to_canvas_pos(mouse: &MousePos, window: &glfw::Window) -> (u16
     return (d_x, d_y);
 }
 
+// Onto the shared canvas while it's on, otherwise our own
+fn draw_on_canvas(shared: &Mutex<SharedCanvas>, draw_pixels: &Mutex<TextureData>, stroke: Stroke) {
+    let mut shared = shared.lock().unwrap();
+    if shared.on {
+        shared.draw(stroke);
+    } else {
+        draw_stroke(&mut draw_pixels.lock().unwrap().data, &stroke);
+    }
+}
 
 fn main() {
-    let infotest = InfoData{
-        msg: InfoMsg::Nothing,
-        number: 0
-    };
-    let infosize = serialized_size(&infotest).unwrap() as usize;
-    println!("is: {infosize}");
+    logging::init();
+    let args = args::parse();
+    let mut profile = Profile::load();
+
     let mut previous_time = Instant::now();
     let mut delta_time: f32 = 0.0;
     
@@ -200,41 +189,10 @@ fn main() {
 
     let cam = Arc::new(Mutex::new(CameraStuff::new()));
 
-    let mut myname = String::new();
-
-    println!("What would you like your name to be?");
-    loop {
-        io::stdin()
-                .read_line(&mut myname)
-                .expect("Failed to read line");
-
-        if myname.len() > 24 {
-            println!("Name cannot be longer than 24 characters. Please type a shorter name.");
-        } else if myname.len() < 2 {
-            println!("Name cannot be shorter than 2 characters. Please type a longer name.");
-        } else {
-            break;
-        }
-    }
-
-    let mut serverip = String::new();
-    println!("Please type the server IP in the format address:port");
-    loop {
-        io::stdin()
-                .read_line(&mut serverip)
-                .expect("Failed to read line");
-        println!("Trying to connect to {serverip}");
-        serverip = serverip.trim().to_string();
-        break;
-    }
-
-    let mut gotHistoryLength = false;
-    let mut gotHistory = false;
-
-    let should_close = Arc::new(AtomicBool::new(false));
+    let mut shown_status = String::new();
     
     let mut mouse = MousePos::new();
-    let penstate = Arc::new(Mutex::new(PenState::new(PenType::ThinPen)));
+    let penstate = Arc::new(Mutex::new(PenState::new(profile.pen.unwrap_or(PenType::ThinPen))));
 
     let mut glfw = glfw::init(glfw::fail_on_errors).unwrap();
 
@@ -257,10 +215,21 @@ fn main() {
     window.make_current();
 
     let mut gl_setup = GlSetup::new();
-    let draw_pixels = Arc::new(Mutex::new(TextureData::new(&myname)));
+    let mut fixtures = Arc::new(Mutex::new(Fixtures::new().unwrap()));
+
+    let Some(login) = login::run(&mut glfw, &mut window, &events, &fixtures.lock().unwrap(), gl_setup.menu_shader, &args, &profile) else {
+        return;
+    };
+    let myname = login.name;
+    profile.name = myname.clone();
+    profile.remember_server(&login.server);
+    let key = *profile.key.get_or_insert_with(psrs_client::new_key);
+    profile.save();
+    (width, height) = window.get_framebuffer_size();
+
+    let draw_pixels = Arc::new(Mutex::new(blank_drawing(&myname)));
     let cam_pixels: Arc<Mutex<Vec<u8>>> = Arc::new(Mutex::new(vec![0u8; 200*200]));
     let text_pixels: Arc<Mutex<Vec<u8>>> = Arc::new(Mutex::new(vec![127u8; 200*200]));
-    let mut fixtures = Arc::new(Mutex::new(Fixtures::new().unwrap()));
     let mut typer = Arc::new(Mutex::new(Typer::new()));
 
     let mut fixtureswapqueue: Arc<Mutex<Vec<FixtureSwap>>> = Arc::new(Mutex::new(Vec::new()));
@@ -270,21 +239,29 @@ fn main() {
 
     let history = Arc::new(Mutex::new(ChatHistory::new()));
 
-
-    let recv_connection = TcpStream::connect(serverip).unwrap();
-    let send_connection = recv_connection.try_clone().unwrap();
-
-    let rconnection = Arc::new(Mutex::new(recv_connection));
-    let sconnection = Arc::new(Mutex::new(send_connection));
+    let outbox = Outbox::load(profile.outbox_path(&myname, &login.server));
+    let network = Network::start(login.connection, myname.clone(), key, outbox);
+    let mut status = String::new();
+    let mut activity = Activity::new(network.handle());
+    let shared = Arc::new(Mutex::new(SharedCanvas::new(network.handle())));
 
     let send_func: Box<dyn Fn()> = {
-        let connection = Arc::clone(&sconnection);
-
+        let network = network.handle();
+        let history = Arc::clone(&history);
+        let shared = Arc::clone(&shared);
 
         let draw_pixels = Arc::clone(&draw_pixels);
         let cam_pixels = Arc::clone(&cam_pixels);
         let text_pixels = Arc::clone(&text_pixels);
         Box::new(move || {
+            // The shared canvas is posted by the server, as it has it
+            let shared = shared.lock().unwrap();
+            if shared.on {
+                shared.post();
+                debug!("Posting shared canvas");
+                return;
+            }
+            drop(shared);
             let mut draw_pixels = draw_pixels.lock().unwrap();
             let cam_pixels = cam_pixels.lock().unwrap();
             let mut text_pixels = text_pixels.lock().unwrap();
@@ -299,17 +276,27 @@ fn main() {
                     draw_pixels.data[i] = text_pixels[i];
                 }
             }
-            send(&draw_pixels, &connection);
+            // Shown as pending straight away, until it comes back from the server
+            history.lock().unwrap().add(draw_pixels.clone());
+            network.send_drawing(draw_pixels.clone());
             (*draw_pixels).data.fill(127);
+            draw_pixels.reply_to = 0;
             (*text_pixels).fill(127);
-            println!("Sending");
+            debug!("Sending drawing");
         })
     };
 
     let clear_func: Box<dyn Fn()> = {
         let draw_pixels = Arc::clone(&draw_pixels);
         let text_pixels = Arc::clone(&text_pixels);
+        let shared = Arc::clone(&shared);
         Box::new(move || {
+            let mut shared = shared.lock().unwrap();
+            if shared.on {
+                shared.clear();
+                return;
+            }
+            drop(shared);
             let mut draw_pixels = draw_pixels.lock().unwrap();
             let mut text_pixels = text_pixels.lock().unwrap();
             (*draw_pixels).data.fill(127);
@@ -375,20 +362,7 @@ fn main() {
             let mut pens = pens.lock().unwrap();
             let mut fixswaps = fixswaps.lock().unwrap();
             pens.pentype = pens.pentype.next();
-            let (newx, newy) = match pens.pentype {
-                PenType::ThinPen => {
-                    (8, 0)
-                },
-                PenType::FatPen => {
-                    (9, 0)
-                },
-                PenType::HugePen => {
-                    (10, 0)
-                },
-                PenType::TinyPen => {
-                    (11, 0)
-                }
-            };
+            let (newx, newy) = pens.pentype.tex_coords();
             fixswaps.push(FixtureSwap{
                 tooltip: String::from("Swap Pen"), 
                 newtexx: newx, 
@@ -420,6 +394,14 @@ fn main() {
         })
     };
 
+    let shared_canvas_func: Box<dyn Fn()> = {
+        let shared = Arc::clone(&shared);
+        Box::new(move || {
+            shared.lock().unwrap().toggle();
+        })
+    };
+
+    let (pen_texx, pen_texy) = penstate.lock().unwrap().pentype.tex_coords();
     fixtures.lock().unwrap().set_fixtures(vec![
         Fixture {x:-1.0, y: -1.0, width: 0.2, height: 0.1, tooltip: String::from("Clear Drawing"), texx: 6, texy: 0, func: clear_func},
         Fixture {x:-0.8, y: -1.0, width: 0.2, height: 0.1, tooltip: String::from("Brightnesss Down"), texx: 5, texy: 0, func: brightdown_func},
@@ -427,12 +409,11 @@ fn main() {
         Fixture {x:-0.4, y: -1.0, width: 0.2, height: 0.1, tooltip: String::from("Toggle Camera"), texx: 3, texy: 0, func: cam_func},
         Fixture {x:-0.2, y: -1.0, width: 0.2, height: 0.1, tooltip: String::from("Send Drawing"), texx: 1, texy: 0, func: send_func},
         Fixture {x:0.0, y: -1.0, width: 0.2, height: 0.1, tooltip: String::from("Text Mode"), texx: 12, texy: 0, func: toggle_text_func},
+        Fixture {x:0.2, y: -1.0, width: 0.2, height: 0.1, tooltip: String::from("Shared Canvas"), texx: 2, texy: 0, func: shared_canvas_func},
         Fixture {x:0.8, y: 0.0, width: 0.2, height: 0.1, tooltip: String::from("Scroll To Present"), texx: 7, texy: 0, func: jump_to_present_func},
-        Fixture {x:0.8, y: -1.0, width: 0.2, height: 0.1, tooltip: String::from("Swap Pen"), texx: 8, texy: 0, func: swap_pens_func}
+        Fixture {x:0.8, y: -1.0, width: 0.2, height: 0.1, tooltip: String::from("Swap Pen"), texx: pen_texx, texy: pen_texy, func: swap_pens_func}
     ]);
 
-    
-    let mut recv_jh: Option<JoinHandle<()>> = None;
 
 
     while !window.should_close() {
@@ -458,12 +439,19 @@ fn main() {
                 flash_window(window_handle);
             }
             history.lock().unwrap().draw_names(width, height, gl_setup.scroll_shader, lock_fixtures.texture);
-            gl_setup.update_texture(&draw_pixels.lock().unwrap().data);
+            let lock_shared = shared.lock().unwrap();
+            if lock_shared.on {
+                gl_setup.update_texture(&lock_shared.data);
+            } else {
+                gl_setup.update_texture(&draw_pixels.lock().unwrap().data);
+            }
+            drop(lock_shared);
             gl_setup.update_cam_texture(&cam_pixels.lock().unwrap());
             gl_setup.update_text_texture(&text_pixels.lock().unwrap());
             gl_setup.draw();
             lock_fixtures.draw(gl_setup.menu_shader);
             lock_fixtures.draw_tooltip(&window, gl_setup.menu_shader);
+            activity.draw(width, height, gl_setup.menu_shader, &lock_fixtures);
         }
         lock_fixtures.get_moused_over(&mouse, width, height);
         unsafe {
@@ -474,6 +462,38 @@ fn main() {
         }
         drop(lock_fixtures);
 
+        for event in network.events() {
+            match event {
+                NetEvent::History(items) => history.lock().unwrap().replace(items),
+                NetEvent::Drawing(texture_data) => {
+                    activity.sent(&psrs_protocol::display_name(&texture_data.name));
+                    history.lock().unwrap().add(texture_data);
+                },
+                NetEvent::Deleted(message_id) => history.lock().unwrap().remove(message_id),
+                NetEvent::Refused(texture_data) => history.lock().unwrap().withdraw(&texture_data),
+                NetEvent::Activity(name) => activity.heard(name),
+                NetEvent::Board(data) => shared.lock().unwrap().received(data),
+                NetEvent::Stroke(stroke) => shared.lock().unwrap().heard(&stroke),
+                NetEvent::BoardCleared => shared.lock().unwrap().cleared(),
+                NetEvent::Reactions(counts) => history.lock().unwrap().set_reactions(counts),
+                NetEvent::Status(text) => status = text
+            }
+        }
+        let reply_to = draw_pixels.lock().unwrap().reply_to;
+        let mut title = status.clone();
+        if shared.lock().unwrap().on {
+            title.push_str(" (shared canvas)");
+        } else if reply_to != 0 {
+            match history.lock().unwrap().find(reply_to) {
+                Some(parent) => title.push_str(&format!(" (replying to {})", psrs_protocol::display_name(&parent.name))),
+                None => title.push_str(" (replying)")
+            }
+        }
+        if title != shown_status {
+            window.set_title(&format!("PictoSend RS - {title}"));
+            shown_status = title;
+        }
+
         let lock_cam = cam.lock().unwrap();
         if lock_cam.camera_mode {
             if cam_timer > 0.25 {
@@ -494,61 +514,6 @@ fn main() {
             }
         }
         drop(lock_cam);
-            
-        if !gotHistoryLength || !gotHistory {
-
-            let mut locked_conn = rconnection.lock().unwrap();
-
-            request_history_length(&mut locked_conn);
-            println!("Requested history length");
-
-            let mut buffer = [0; crate::network::PACKET_SIZE];
-
-            match (locked_conn).read(&mut buffer) {
-                Ok(by) => {
-                    println!("Got a response of {by} bytes");
-                    let received_data: InfoData = bincode::deserialize(&buffer[..infosize]).unwrap();
-                    let history_size = received_data.number;
-                    println!("History length is {history_size}");
-                    gotHistoryLength = true;
-
-
-                    request_history(&mut locked_conn);
-                    println!("Requested history, expecting {history_size} bytes");
-
-                    let mut history_buffer = vec![0; history_size as usize];
-
-                    match (locked_conn).read(&mut history_buffer) {
-                        Ok(bb) => {
-                            println!("Received {bb} bytes of history");
-                            let history_vec: Vec<TextureData> = bincode::deserialize(&history_buffer).unwrap();
-                            gotHistory = true;
-                            let mut his = history.lock().unwrap();
-                            his.history = history_vec;
-                            his.dirty = true;
-                            println!("Confirming history");
-                            confirm_history(&mut locked_conn);
-                        }
-                        Err(e) => {
-                            println!("Failed to read from server: {}", e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    println!("Failed to read from server: {}", e);
-                }
-            }
-            drop(locked_conn);
-            //(cloned_stream).set_nonblocking(true).unwrap();
-
-            let connection_clone = Arc::clone(&rconnection);
-            let history_clone = Arc::clone(&history);
-            let should_close_clone = Arc::clone(&should_close);
-
-            recv_jh = Some(std::thread::spawn(move || {
-                receive(&history_clone, &connection_clone, &should_close_clone);
-            }));
-        }
 
 
         for (_, event) in glfw::flush_messages(&events) {
@@ -556,11 +521,42 @@ fn main() {
                 glfw::WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
                     window.set_should_close(true)
                 },
+                glfw::WindowEvent::Key(Key::Delete, _, Action::Press, _) => {
+                    let his = history.lock().unwrap();
+                    if let Some(index) = his.item_at(mouse.x, mouse.y, width, height) {
+                        if his.history[index].name == draw_pixels.lock().unwrap().name {
+                            network.handle().delete(his.history[index].id);
+                        }
+                    }
+                    drop(his);
+                },
+                // Starts a reply to the drawing under the mouse, or stops replying if there's none
+                glfw::WindowEvent::Key(Key::R, _, Action::Press, _) if !typer.lock().unwrap().started => {
+                    let his = history.lock().unwrap();
+                    match his.item_at(mouse.x, mouse.y, width, height) {
+                        // Ours still on its way has no id to point at yet
+                        Some(index) if his.history[index].id == 0 => {},
+                        Some(index) => draw_pixels.lock().unwrap().reply_to = his.history[index].id,
+                        None => draw_pixels.lock().unwrap().reply_to = 0
+                    }
+                    drop(his);
+                },
+                // Stamps the drawing under the mouse, or takes the stamp back if it's already ours
+                glfw::WindowEvent::Key(key @ (Key::Num1 | Key::Num2 | Key::Num3 | Key::Num4), _, Action::Press, _) if !typer.lock().unwrap().started => {
+                    let reaction = Reaction::ALL[key as usize - Key::Num1 as usize];
+                    let his = history.lock().unwrap();
+                    match his.item_at(mouse.x, mouse.y, width, height) {
+                        Some(index) if his.history[index].id != 0 => network.handle().react(his.history[index].id, reaction),
+                        _ => {}
+                    }
+                    drop(his);
+                },
                 glfw::WindowEvent::Key(Key::Backspace, _, Action::Press, _) => {
                     let mut typerlock = typer.lock().unwrap();
 
                     if typerlock.started {
                         typerlock.backspace(&mut text_pixels.lock().unwrap());
+                        activity.ours();
                     }
 
                     drop(typerlock);
@@ -570,6 +566,7 @@ fn main() {
 
                     if typerlock.started {
                         typerlock.type_letter(&mut text_pixels.lock().unwrap(), 10, &fixtures.lock().unwrap().guitexpixels);
+                        activity.ours();
                     }
 
                     drop(typerlock);
@@ -579,6 +576,7 @@ fn main() {
                     let mut typerlock = typer.lock().unwrap();
                     if typerlock.started {
                         typerlock.type_letter(&mut text_pixels.lock().unwrap(), code as u8, &fixtures.lock().unwrap().guitexpixels);
+                        activity.ours();
                     }
                     drop(typerlock);
                 },
@@ -637,11 +635,15 @@ fn main() {
                     glfw::MouseButtonLeft => {
                         let mut lock_fixtures = fixtures.lock().unwrap();
                         if lock_fixtures.moused_over_id == 0.0 {
-                            draw_pixels.lock().unwrap().draw(&mouse, &penstate.lock().unwrap(), width, height, 254);
+                            let stroke = mouse.stroke(&penstate.lock().unwrap(), width, height, 254);
+                            draw_on_canvas(&shared, &draw_pixels, stroke);
+                            activity.ours();
                         }
                     },
                     glfw::MouseButtonRight => {
-                        draw_pixels.lock().unwrap().draw(&mouse, &penstate.lock().unwrap(), width, height, 0);
+                        let stroke = mouse.stroke(&penstate.lock().unwrap(), width, height, 0);
+                        draw_on_canvas(&shared, &draw_pixels, stroke);
+                        activity.ours();
                     },
                     _ => ()
                 }
@@ -651,9 +653,7 @@ fn main() {
 
         window.swap_buffers();
     }
-    should_close.store(true, Ordering::Relaxed);
-    recv_jh.unwrap().join().unwrap();
-
-    rconnection.lock().unwrap().shutdown(Shutdown::Both).unwrap();
-    sconnection.lock().unwrap().shutdown(Shutdown::Both).unwrap();
+    profile.pen = Some(penstate.lock().unwrap().pentype);
+    profile.save();
+    network.shutdown();
 }
@@ -1,104 +1,490 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+// Everything that talks to the server (or LAN peers) runs on a thread of its own.
+// The UI queues drawings and deletes through a NetworkHandle and picks up what
+// happened with Network::events, so the render loop never waits on a socket.
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use serde::{Deserialize, Serialize};
+use psrs_client::lan::LanPeer;
+use psrs_client::{Client, Event};
+use psrs_protocol::canvas::Stroke;
+use psrs_protocol::{ProtocolError, Reaction, ReactionCounts, TextureData, KEY_SIZE};
+use tracing::{debug, error, info, warn};
 
-use crate::TextureData;
-use crate::history::ChatHistory;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::login::Connection;
+use crate::outbox::Outbox;
 
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(3);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// How often drawings that couldn't go out are tried again
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
 
+// Error codes the server sends back with InfoMsg::Error
+pub fn describe_error(code: i32) -> &'static str {
+    match ProtocolError::from_code(code) {
+        Some(ProtocolError::Malformed) => "malformed message",
+        Some(ProtocolError::UnknownMessage) => "unknown message type",
+        Some(ProtocolError::UnexpectedMessage) => "unexpected message",
+        Some(ProtocolError::BadCanvasSize) => "wrong canvas size",
+        Some(ProtocolError::BadName) => "invalid name",
+        Some(ProtocolError::BadTimestamp) => "bad timestamp",
+        Some(ProtocolError::TooLarge) => "message too large",
+        None => "unknown error"
+    }
+}
+
+// What the network thread tells the UI
+pub enum NetEvent {
+    // Everything to show after (re)joining: the server's history plus ours still on its way
+    History(Vec<TextureData>),
+    Drawing(TextureData),
+    Deleted(i32),
+    // Ours that the server wouldn't take, so it's no longer on its way
+    Refused(TextureData),
+    // Someone else is drawing
+    Activity(String),
+    // The whole shared canvas, whenever we (re)join it
+    Board(Vec<u8>),
+    // Someone else drew on the shared canvas
+    Stroke(Stroke),
+    BoardCleared,
+    // A message's reaction totals changed
+    Reactions(ReactionCounts),
+    // For the window title
+    Status(String)
+}
+
+enum Command {
+    Send(TextureData),
+    Delete(i32),
+    Activity,
+    JoinBoard,
+    LeaveBoard,
+    Stroke(Stroke),
+    ClearBoard,
+    PostBoard,
+    React(i32, Reaction),
+    // From the thread reading a connection, tagged with which connection it was
+    Received(u64, Result<Event, psrs_client::Error>),
+    Shutdown
+}
+
+// For queueing work from anywhere, e.g. the fixture callbacks
+#[derive(Clone)]
+pub struct NetworkHandle {
+    commands: mpsc::Sender<Command>
+}
+
+impl NetworkHandle {
+    // Goes out as soon as we're connected, and survives a restart until it has (see Outbox)
+    pub fn send_drawing(&self, texture_data: TextureData) {
+        let _ = self.commands.send(Command::Send(texture_data));
+    }
+
+    pub fn delete(&self, message_id: i32) {
+        let _ = self.commands.send(Command::Delete(message_id));
+    }
+
+    // Only worth anything right now, so it's dropped rather than queued while disconnected
+    pub fn activity(&self) {
+        let _ = self.commands.send(Command::Activity);
+    }
 
-pub const PACKET_SIZE: usize = 40055;
-const MAX_HISTORY: usize = 56;
+    // Stays joined across reconnects until left; the server sends the board each time
+    pub fn join_board(&self) {
+        let _ = self.commands.send(Command::JoinBoard);
+    }
 
-// #[derive(Clone, Debug)]
-// pub struct Connection {
-//     pub stream: Arc<Mutex<TcpStream>>
-// }
+    pub fn leave_board(&self) {
+        let _ = self.commands.send(Command::LeaveBoard);
+    }
 
-//     pub fn new(address: &String) -> Connection {
-//         let stream = TcpStream::connect(address).unwrap();
-//         stream.set_nonblocking(true).unwrap();
+    // Like activity, strokes are dropped while disconnected; rejoining brings the board up to date
+    pub fn stroke(&self, stroke: Stroke) {
+        let _ = self.commands.send(Command::Stroke(stroke));
+    }
 
-//         Connection {
-//             stream: Arc::new(Mutex::new(stream))
-//         }
-//     }
+    pub fn clear_board(&self) {
+        let _ = self.commands.send(Command::ClearBoard);
+    }
 
-#[derive(Serialize, Deserialize, PartialEq)]
-pub enum InfoMsg {
-    RequestHistoryLength,
-    HistoryLength,
-    RequestHistory,
-    ConfirmReceivedHistory,
-    Nothing
+    pub fn post_board(&self) {
+        let _ = self.commands.send(Command::PostBoard);
+    }
+
+    // Reacting again with the same stamp takes it back
+    pub fn react(&self, message_id: i32, reaction: Reaction) {
+        let _ = self.commands.send(Command::React(message_id, reaction));
+    }
 }
-#[derive(Serialize, Deserialize)]
-pub struct InfoData {
-    pub msg: InfoMsg,
-    pub number: i32
+
+pub struct Network {
+    handle: NetworkHandle,
+    events: Receiver<NetEvent>,
+    thread: JoinHandle<()>
 }
 
+impl Network {
+    pub fn start(connection: Connection, name: String, key: [u8; KEY_SIZE], outbox: Outbox) -> Network {
+        let (commands, received) = mpsc::channel();
+        let (events_sender, events) = mpsc::channel();
+        let worker = Worker { commands: commands.clone(), received, events: events_sender, outbox, name, key, on_board: false, generation: 0 };
+        let thread = thread::spawn(move || worker.run(connection));
+        Network { handle: NetworkHandle { commands }, events, thread }
+    }
 
-pub fn receive(history: &Arc<Mutex<ChatHistory>>, stream: &Arc<Mutex<TcpStream>>, should_close: &Arc<AtomicBool>) {
-    stream.lock().unwrap().set_read_timeout(Some(Duration::from_secs(1))).unwrap();
-    while !should_close.load(Ordering::Relaxed) {
-        let mut buffer = [0; PACKET_SIZE];
-        let mut stream = stream.lock().unwrap();
-        match stream.read_exact(&mut buffer) {
-            Ok(_) => {
-                let received_texture_data: TextureData = bincode::deserialize(&buffer).unwrap();
-                history.lock().unwrap().history.push(received_texture_data);
-                if history.lock().unwrap().history.len() > MAX_HISTORY {
-                    history.lock().unwrap().history.remove(0);
-                }
-                history.lock().unwrap().history.sort_by_key(|item| item.timestamp);
-                history.lock().unwrap().dirty = true;
-                println!("Received drawing from server");
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                // No incoming message
-            }
-            Err(e) => {
-                //println!("Failed to read from server: {}", e);
-            }
+    pub fn handle(&self) -> NetworkHandle {
+        self.handle.clone()
+    }
+
+    // Whatever has happened since the last call, without waiting
+    pub fn events(&self) -> mpsc::TryIter<'_, NetEvent> {
+        self.events.try_iter()
+    }
+
+    // Disconnects and waits for the thread, which can take up to CONNECT_TIMEOUT
+    // if it's in the middle of reconnecting
+    pub fn shutdown(self) {
+        let _ = self.handle.commands.send(Command::Shutdown);
+        if self.thread.join().is_err() {
+            error!("Network thread panicked");
         }
     }
 }
 
-pub fn send(texture_data: &TextureData, stream: &Arc<Mutex<TcpStream>>) {
-    let mut stream = stream.lock().unwrap();
-    let serialized_data = bincode::serialize(&texture_data).unwrap();
-    stream.write_all(&serialized_data).unwrap();
+// Where our drawings go: a server, or in LAN mode straight to the other peers
+enum Link {
+    Server(psrs_client::Sender),
+    Lan(LanPeer)
 }
-pub fn confirm_history(stream: &mut TcpStream) {
-    let info_data = InfoData {
-        msg: InfoMsg::ConfirmReceivedHistory,
-        number: 0
-    };
-
-    let serialized_data = bincode::serialize(&info_data).unwrap();
-    stream.write_all(&serialized_data).unwrap();
+
+impl Link {
+    fn send(&self, texture_data: &TextureData) -> Result<(), String> {
+        match self {
+            Link::Server(sender) => sender.resend(texture_data),
+            Link::Lan(peer) => peer.resend(texture_data)
+        }.map_err(|e| e.to_string())
+    }
+
+    fn delete(&self, message_id: i32) {
+        match self {
+            Link::Server(sender) => {
+                match sender.delete(message_id) {
+                    Ok(()) => info!(message_id, "Requested deletion of message"),
+                    Err(e) => warn!(message_id, error = %e, "Not connected, can't delete")
+                }
+            },
+            // Every peer keeps its own history and nobody could be trusted to enforce authorship
+            Link::Lan(_) => warn!(message_id, "Drawings can't be deleted in LAN mode")
+        }
+    }
+
+    // Reactions are counted by the server, so like deleting there's nothing to do between peers
+    fn react(&self, message_id: i32, reaction: Reaction) {
+        match self {
+            Link::Server(sender) => {
+                if let Err(e) = sender.react(message_id, reaction) {
+                    warn!(message_id, error = %e, "Not connected, can't react");
+                }
+            },
+            Link::Lan(_) => warn!(message_id, "Reactions aren't available in LAN mode")
+        }
+    }
+
+    fn activity(&self) {
+        let sent = match self {
+            Link::Server(sender) => sender.activity(),
+            Link::Lan(peer) => peer.activity()
+        };
+        if let Err(e) = sent {
+            debug!(error = %e, "Couldn't send activity");
+        }
+    }
+
+    // The shared canvas lives on the server, so there's none between LAN peers
+    fn board(&self, what: &str, send: impl FnOnce(&psrs_client::Sender) -> Result<(), psrs_client::Error>) {
+        match self {
+            Link::Server(sender) => {
+                if let Err(e) = send(sender) {
+                    debug!(error = %e, "Couldn't {what}");
+                }
+            },
+            Link::Lan(_) => warn!("Can't {what} in LAN mode, there's no shared canvas")
+        }
+    }
 }
-pub fn request_history(stream: &mut TcpStream) {
-    let info_data = InfoData {
-        msg: InfoMsg::RequestHistory,
-        number: 0
-    };
-
-    let serialized_data = bincode::serialize(&info_data).unwrap();
-    stream.write_all(&serialized_data).unwrap();
+
+// Why serve() returned
+enum Ended {
+    // The connection is gone but the server may well come back
+    Lost,
+    // Like Lost, but the server said it was going, which stays in the title while we wait
+    ShutDown,
+    // We're closing, or were told to go away
+    Closed
+}
+
+struct Worker {
+    // Handed to reader threads
+    commands: mpsc::Sender<Command>,
+    received: Receiver<Command>,
+    events: mpsc::Sender<NetEvent>,
+    outbox: Outbox,
+    name: String,
+    // Said with every hello, so our messages stay ours to delete across reconnects
+    key: [u8; KEY_SIZE],
+    // Whether we're on the shared canvas, so it can be rejoined after reconnecting
+    on_board: bool,
+    // Which connection's reader we're listening to; older ones are ignored
+    generation: u64
 }
-pub fn request_history_length(stream: &mut TcpStream) {
 
-    let info_data = InfoData {
-        msg: InfoMsg::RequestHistoryLength,
-        number: 0
-    };
+impl Worker {
+    fn run(self, connection: Connection) {
+        match connection {
+            Connection::Server(stream) => self.run_server(stream),
+            Connection::Lan(peer) => self.run_lan(peer)
+        }
+    }
+
+    fn run_server(mut self, stream: TcpStream) {
+        let addr = match stream.peer_addr() {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                warn!(error = %e, "Don't know where we're connected to, won't be able to reconnect");
+                None
+            }
+        };
+        let mut next = Some(stream);
+        let mut rejoining = false;
+        loop {
+            let stream = match next.take() {
+                Some(stream) => stream,
+                None => match addr.and_then(|addr| self.reconnect(addr)) {
+                    Some(stream) => stream,
+                    None => return
+                }
+            };
+            let status = match self.join(stream) {
+                Ok(sender) => {
+                    if rejoining {
+                        info!("Reconnected to server");
+                        self.status("Reconnected");
+                    }
+                    let ended = self.serve(&Link::Server(sender.clone()));
+                    sender.disconnect();
+                    match ended {
+                        Ended::Closed => return,
+                        Ended::ShutDown => "Server shutting down, reconnecting...",
+                        Ended::Lost => "Disconnected, reconnecting..."
+                    }
+                }
+                Err(e) => {
+                    error!(error = %e, "Couldn't join the server");
+                    "Disconnected, reconnecting..."
+                }
+            };
+            self.status(status);
+            rejoining = true;
+        }
+    }
+
+    // The join handshake, then a reader thread for whatever comes after it
+    fn join(&mut self, stream: TcpStream) -> Result<psrs_client::Sender, psrs_client::Error> {
+        let mut client = Client::from_stream(stream, &self.name)?;
+        client.set_key(self.key);
+        // A server that accepts but never answers shouldn't keep us here forever
+        client.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        let fetched = client.fetch_history()?;
+        client.set_read_timeout(None)?;
+        debug!(count = fetched.len(), "Fetched history");
+
+        self.outbox.confirm_all(&fetched);
+        self.outbox.reconnected();
+        let mut shown = fetched;
+        shown.extend(self.outbox.pending().cloned());
+        let _ = self.events.send(NetEvent::History(shown));
+
+        let sender = client.sender();
+        self.spawn_reader(move || client.next_event());
+        Ok(sender)
+    }
+
+    // Peers have no history to hand out, so LAN mode starts with just our pending drawings
+    fn run_lan(mut self, peer: LanPeer) {
+        let mut reader = match peer.try_clone() {
+            Ok(reader) => reader,
+            Err(e) => {
+                error!(error = %e, "Couldn't listen for LAN peers");
+                self.status("Disconnected from LAN");
+                return;
+            }
+        };
+        self.status("LAN mode, no server");
+        let _ = self.events.send(NetEvent::History(self.outbox.pending().cloned().collect()));
+        // Nothing stops this reader, it goes when the program does
+        self.spawn_reader(move || reader.next_event());
+        if let Ended::Lost | Ended::ShutDown = self.serve(&Link::Lan(peer)) {
+            self.status("Disconnected from LAN");
+        }
+    }
 
-    let serialized_data = bincode::serialize(&info_data).unwrap();
-    stream.write_all(&serialized_data).unwrap();
+    fn spawn_reader(&mut self, mut next_event: impl FnMut() -> Result<Event, psrs_client::Error> + Send + 'static) {
+        self.generation += 1;
+        let generation = self.generation;
+        let commands = self.commands.clone();
+        thread::spawn(move || loop {
+            let event = next_event();
+            let failed = event.is_err();
+            if commands.send(Command::Received(generation, event)).is_err() || failed {
+                return;
+            }
+        });
+    }
+
+    // Handles commands and events for one connection until it's lost or we're done
+    fn serve(&mut self, link: &Link) -> Ended {
+        self.outbox.flush(|texture_data| link.send(texture_data));
+        if self.on_board {
+            link.board("rejoin the shared canvas", |sender| sender.join_board());
+        }
+        loop {
+            let command = match self.received.recv_timeout(RETRY_INTERVAL) {
+                Ok(command) => command,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.outbox.flush(|texture_data| link.send(texture_data));
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ended::Closed
+            };
+            match command {
+                Command::Send(texture_data) => {
+                    self.outbox.push(texture_data);
+                    self.outbox.flush(|texture_data| link.send(texture_data));
+                }
+                Command::Delete(message_id) => link.delete(message_id),
+                Command::Activity => link.activity(),
+                Command::JoinBoard => {
+                    self.on_board = true;
+                    link.board("join the shared canvas", |sender| sender.join_board());
+                }
+                Command::LeaveBoard => {
+                    self.on_board = false;
+                    link.board("leave the shared canvas", |sender| sender.leave_board());
+                }
+                Command::Stroke(stroke) => link.board("draw on the shared canvas", |sender| sender.stroke(&stroke)),
+                Command::ClearBoard => link.board("clear the shared canvas", |sender| sender.clear_board()),
+                Command::PostBoard => link.board("post the shared canvas", |sender| sender.post_board()),
+                Command::React(message_id, reaction) => link.react(message_id, reaction),
+                Command::Received(generation, _) if generation != self.generation => {}
+                Command::Received(_, Ok(event)) => {
+                    if let Some(ended) = self.handle_event(event) {
+                        return ended;
+                    }
+                }
+                Command::Received(_, Err(e)) => {
+                    error!(error = %e, "Lost connection");
+                    return Ended::Lost;
+                }
+                Command::Shutdown => return Ended::Closed
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) -> Option<Ended> {
+        match event {
+            Event::Drawing(texture_data) => {
+                self.outbox.confirm(&texture_data);
+                let _ = self.events.send(NetEvent::Drawing(texture_data));
+                debug!("Received drawing");
+            }
+            Event::Deleted(message_id) => {
+                info!(message_id, "Message was deleted");
+                let _ = self.events.send(NetEvent::Deleted(message_id));
+            }
+            Event::DeleteRefused(message_id) => {
+                warn!(message_id, "Server refused to delete message");
+            }
+            Event::DrawingRefused(code) => {
+                // A plugin's refusal comes with an announcement saying why, which stays in the title
+                if code != 0 {
+                    warn!(code, "Server refused our drawing: {}", describe_error(code));
+                    self.status(&format!("Drawing refused: {}", describe_error(code)));
+                } else {
+                    warn!("Server refused our drawing");
+                }
+                if let Some(texture_data) = self.outbox.refuse() {
+                    let _ = self.events.send(NetEvent::Refused(texture_data));
+                }
+            }
+            Event::ServerError(code) => {
+                warn!(code, "Server rejected our last message: {}", describe_error(code));
+                self.status(&format!("Server error: {}", describe_error(code)));
+            }
+            Event::Announcement(text) => {
+                info!(%text, "Announcement");
+                self.status(&format!("Announcement: {text}"));
+            }
+            Event::Kicked => {
+                warn!("Kicked by the server");
+                self.status("Kicked by server");
+                return Some(Ended::Closed);
+            }
+            Event::Activity(name) => {
+                let _ = self.events.send(NetEvent::Activity(name));
+            }
+            Event::Board(data) => {
+                debug!("Joined the shared canvas");
+                let _ = self.events.send(NetEvent::Board(data));
+            }
+            Event::Stroke(stroke) => {
+                let _ = self.events.send(NetEvent::Stroke(stroke));
+            }
+            Event::BoardCleared => {
+                let _ = self.events.send(NetEvent::BoardCleared);
+            }
+            Event::Reactions(counts) => {
+                let _ = self.events.send(NetEvent::Reactions(counts));
+            }
+            Event::ServerShutdown => {
+                // It may well be back soon, so we keep trying like for any other lost connection
+                info!("Server is shutting down");
+                return Some(Ended::ShutDown);
+            }
+        }
+        None
+    }
+
+    // Keeps trying to get back to the server until it works or we're closing.
+    // Drawings made in the meantime wait in the outbox.
+    fn reconnect(&mut self, addr: SocketAddr) -> Option<TcpStream> {
+        loop {
+            let retry_at = Instant::now() + RECONNECT_INTERVAL;
+            while let Some(wait) = retry_at.checked_duration_since(Instant::now()) {
+                match self.received.recv_timeout(wait) {
+                    Ok(Command::Send(texture_data)) => self.outbox.push(texture_data),
+                    Ok(Command::Delete(message_id)) => warn!(message_id, "Not connected, can't delete"),
+                    Ok(Command::JoinBoard) => self.on_board = true,
+                    Ok(Command::LeaveBoard) => self.on_board = false,
+                    Ok(Command::PostBoard) => warn!("Not connected, can't post the shared canvas"),
+                    Ok(Command::React(message_id, _)) => warn!(message_id, "Not connected, can't react"),
+                    // Drawn over by the board we get on rejoining
+                    Ok(Command::Stroke(_)) | Ok(Command::ClearBoard) => {}
+                    // Whatever the old connection's reader had left to say, and nobody to tell we're drawing
+                    Ok(Command::Received(..)) | Ok(Command::Activity) => {}
+                    Ok(Command::Shutdown) | Err(RecvTimeoutError::Disconnected) => return None,
+                    Err(RecvTimeoutError::Timeout) => break
+                }
+            }
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => return Some(stream),
+                Err(e) => debug!(%addr, error = %e, "Still can't reach the server")
+            }
+        }
+    }
+
+    fn status(&self, text: &str) {
+        let _ = self.events.send(NetEvent::Status(String::from(text)));
+    }
 }
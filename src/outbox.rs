@@ -0,0 +1,147 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+
+use psrs_protocol::TextureData;
+use tracing::{debug, info, warn};
+
+// Drawings we've sent that haven't come back from the server yet. They stay
+// here, and on disk, until they do or turn up in the history after a reconnect,
+// so neither a dropped connection nor a restart loses them. Until then they're
+// shown in the history with id 0, which the server never hands out.
+pub struct Outbox {
+    drawings: VecDeque<TextureData>,
+    // How many from the front have been written to the current connection
+    sent: usize,
+    path: Option<PathBuf>
+}
+
+impl Outbox {
+    pub fn load(path: Option<PathBuf>) -> Outbox {
+        let drawings: Vec<TextureData> = match path.as_ref().map(fs::read) {
+            Some(Ok(bytes)) => bincode::deserialize(&bytes).unwrap_or_else(|e| {
+                warn!(error = %e, "Ignoring unreadable outbox");
+                Vec::new()
+            }),
+            _ => Vec::new()
+        };
+        if !drawings.is_empty() {
+            info!(count = drawings.len(), "Drawings from last time are still waiting to be sent");
+        }
+        Outbox { drawings: drawings.into(), sent: 0, path }
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &TextureData> {
+        self.drawings.iter()
+    }
+
+    pub fn push(&mut self, texture_data: TextureData) {
+        self.drawings.push_back(texture_data);
+        self.persist();
+    }
+
+    // Writes whatever hasn't been written to this connection yet, oldest first,
+    // stopping at the first failure so nothing overtakes anything older
+    pub fn flush(&mut self, mut send: impl FnMut(&TextureData) -> Result<(), String>) {
+        while let Some(texture_data) = self.drawings.get(self.sent) {
+            if let Err(e) = send(texture_data) {
+                warn!(error = %e, waiting = self.drawings.len() - self.sent, "Not connected, drawings will be sent once we are");
+                return;
+            }
+            self.sent += 1;
+        }
+    }
+
+    // A new connection has seen none of them
+    pub fn reconnected(&mut self) {
+        self.sent = 0;
+    }
+
+    // Drops our copy of a drawing that has reached the server; true if it was ours
+    pub fn confirm(&mut self, received: &TextureData) -> bool {
+        let Some(index) = self.drawings.iter().position(|item| same_drawing(item, received)) else {
+            return false;
+        };
+        self.drawings.remove(index);
+        if index < self.sent {
+            self.sent -= 1;
+        }
+        self.persist();
+        true
+    }
+
+    // The server turned down the oldest drawing written to this connection, which
+    // is the front one since it answers in order. It would only be turned down
+    // again, so it's dropped rather than kept for the next connection.
+    pub fn refuse(&mut self) -> Option<TextureData> {
+        if self.sent == 0 {
+            return None;
+        }
+        let refused = self.drawings.pop_front()?;
+        self.sent -= 1;
+        self.persist();
+        Some(refused)
+    }
+
+    // Drops everything the server already has, e.g. ones it stored just before the
+    // connection dropped. Only for a fresh connection, before anything is flushed to it.
+    pub fn confirm_all(&mut self, history: &[TextureData]) {
+        let before = self.drawings.len();
+        self.drawings.retain(|item| !history.iter().any(|stored| same_drawing(item, stored)));
+        if self.drawings.len() != before {
+            self.persist();
+        }
+    }
+
+    fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let saved = if self.drawings.is_empty() {
+            fs::remove_file(path).or_else(|e| if e.kind() == std::io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+        } else {
+            let drawings: Vec<&TextureData> = self.drawings.iter().collect();
+            path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(path, bincode::serialize(&drawings).unwrap()))
+        };
+        match saved {
+            Ok(()) => debug!(waiting = self.drawings.len(), "Saved outbox"),
+            Err(e) => warn!(path = %path.display(), error = %e, "Failed to save outbox")
+        }
+    }
+}
+
+// Ids are only handed out by the server, so a drawing is recognised by its author and when it was made
+pub fn same_drawing(a: &TextureData, b: &TextureData) -> bool {
+    a.name == b.name && a.timestamp == b.timestamp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drawing(timestamp: u128) -> TextureData {
+        TextureData { timestamp, ..crate::blank_drawing(&String::from("alice")) }
+    }
+
+    #[test]
+    fn refused_drawings_are_dropped_and_the_rest_still_wait() {
+        let mut outbox = Outbox::load(None);
+        outbox.push(drawing(1));
+        outbox.push(drawing(2));
+        // Nothing's been written to this connection, so nothing of ours can be refused
+        assert!(outbox.refuse().is_none());
+
+        outbox.flush(|_| Ok(()));
+        assert_eq!(outbox.refuse().map(|refused| refused.timestamp), Some(1));
+        assert_eq!(outbox.pending().map(|item| item.timestamp).collect::<Vec<_>>(), [2]);
+
+        // The one left goes out again on the next connection
+        outbox.reconnected();
+        let mut resent = Vec::new();
+        outbox.flush(|item| {
+            resent.push(item.timestamp);
+            Ok(())
+        });
+        assert_eq!(resent, [2]);
+    }
+}
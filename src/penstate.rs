@@ -1,14 +1,12 @@
+use psrs_protocol::canvas::Pen;
+use serde::{Deserialize, Serialize};
 use crate::fixtures::Fixture;
 
-pub struct OffsetSpot {
-    pub x: i8,
-    pub y: i8
-}
-
 pub struct PenState {
     pub pentype: PenType
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum PenType {
     HugePen,
     FatPen,
@@ -17,112 +15,23 @@ pub enum PenType {
 }
 
 impl PenType {
-    pub fn get_spots(&self) -> &'static [OffsetSpot] {
+    // The shapes live with the canvas code so servers and other clients draw strokes the same
+    pub fn pen(&self) -> Pen {
         match self {
-            PenType::FatPen => &[
-                OffsetSpot{x: 0, y: 0},
-
-                OffsetSpot{x: 1, y: 0},
-                OffsetSpot{x: -1, y: 0},
-                OffsetSpot{x: 0, y: 1},
-                OffsetSpot{x: 0, y: -1},
-
-                OffsetSpot{x: 1, y: 1},
-                OffsetSpot{x: -1, y: 1},
-                OffsetSpot{x: 1, y: -1},
-                OffsetSpot{x: -1, y: -1},
-
-                OffsetSpot{x: -1, y: 2},
-                OffsetSpot{x: 0, y: 2},
-                OffsetSpot{x: 1, y: 2},
-
-                OffsetSpot{x: -1, y: -2},
-                OffsetSpot{x: 0, y: -2},
-                OffsetSpot{x: 1, y: -2},
-
-                OffsetSpot{x: 2, y: -1},
-                OffsetSpot{x: 2, y: 0},
-                OffsetSpot{x: 2, y: 1},
-
-                OffsetSpot{x: -2, y: -1},
-                OffsetSpot{x: -2, y: 0},
-                OffsetSpot{x: -2, y: 1},
-            ],
-            PenType::ThinPen => &[
-                OffsetSpot{x: 0, y: 0},
-
-                OffsetSpot{x: 1, y: 0},
-                OffsetSpot{x: -1, y: 0},
-                OffsetSpot{x: 0, y: 1},
-                OffsetSpot{x: 0, y: -1},
-
-                OffsetSpot{x: 1, y: 1},
-                OffsetSpot{x: -1, y: 1},
-                OffsetSpot{x: 1, y: -1},
-                OffsetSpot{x: -1, y: -1},
-            ],
-            PenType::TinyPen => &[
-                OffsetSpot{x: 0, y: 0},
-            ],
-            PenType::HugePen => & [
-                OffsetSpot{x: 0, y: 0},
-
-                OffsetSpot{x: 1, y: 0},
-                OffsetSpot{x: -1, y: 0},
-                OffsetSpot{x: 0, y: 1},
-                OffsetSpot{x: 0, y: -1},
-
-                OffsetSpot{x: 1, y: 1},
-                OffsetSpot{x: -1, y: 1},
-                OffsetSpot{x: 1, y: -1},
-                OffsetSpot{x: -1, y: -1},
-
-                OffsetSpot{x: -1, y: 2},
-                OffsetSpot{x: 0, y: 2},
-                OffsetSpot{x: 1, y: 2},
-
-                OffsetSpot{x: -1, y: -2},
-                OffsetSpot{x: 0, y: -2},
-                OffsetSpot{x: 1, y: -2},
-
-                OffsetSpot{x: 2, y: -1},
-                OffsetSpot{x: 2, y: 0},
-                OffsetSpot{x: 2, y: 1},
-
-                OffsetSpot{x: -2, y: -1},
-                OffsetSpot{x: -2, y: 0},
-                OffsetSpot{x: -2, y: 1},
-
-                
-                OffsetSpot{x: -3, y: -2},
-                OffsetSpot{x: -3, y: -1},
-                OffsetSpot{x: -3, y: 0},
-                OffsetSpot{x: -3, y: 1},
-                OffsetSpot{x: -3, y: 2},
-
-                OffsetSpot{x: 3, y: -2},
-                OffsetSpot{x: 3, y: -1},
-                OffsetSpot{x: 3, y: 0},
-                OffsetSpot{x: 3, y: 1},
-                OffsetSpot{x: 3, y: 2},
-
-                OffsetSpot{x: -2, y: 3},
-                OffsetSpot{x: -1, y: 3},
-                OffsetSpot{x: 0, y: 3},
-                OffsetSpot{x: 1, y: 3},
-                OffsetSpot{x: 2, y: 3},
-
-                OffsetSpot{x: -2, y: -3},
-                OffsetSpot{x: -1, y: -3},
-                OffsetSpot{x: 0, y: -3},
-                OffsetSpot{x: 1, y: -3},
-                OffsetSpot{x: 2, y: -3},
+            PenType::HugePen => Pen::Huge,
+            PenType::FatPen => Pen::Fat,
+            PenType::ThinPen => Pen::Thin,
+            PenType::TinyPen => Pen::Tiny
+        }
+    }
 
-                OffsetSpot{x: -2, y: -2},
-                OffsetSpot{x: 2, y: -2},
-                OffsetSpot{x: 2, y: 2},
-                OffsetSpot{x: -2, y: 2},
-            ]
+    // Where the Swap Pen fixture's icon for this pen is in gui.png
+    pub fn tex_coords(&self) -> (i8, i8) {
+        match self {
+            PenType::ThinPen => (8, 0),
+            PenType::FatPen => (9, 0),
+            PenType::HugePen => (10, 0),
+            PenType::TinyPen => (11, 0)
         }
     }
 
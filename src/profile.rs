@@ -0,0 +1,88 @@
+use std::fs;
+use std::path::PathBuf;
+
+use psrs_protocol::KEY_SIZE;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::penstate::PenType;
+
+const MAX_RECENT_SERVERS: usize = 5;
+
+// What the client remembers between runs, kept as TOML in the user's config
+// directory (or wherever PSRS_PROFILE points)
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub name: String,
+    // Most recently used first
+    pub servers: Vec<String>,
+    pub pen: Option<PenType>,
+    // What the server knows our messages by, so they can still be deleted after
+    // a restart. Made up the first time we connect.
+    pub key: Option<[u8; KEY_SIZE]>,
+    #[serde(skip)]
+    path: Option<PathBuf>
+}
+
+impl Profile {
+    // A missing or unreadable profile just means starting from scratch
+    pub fn load() -> Profile {
+        let Some(path) = profile_path() else {
+            debug!("No config directory, not remembering anything");
+            return Profile::default();
+        };
+        let mut profile = match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).unwrap_or_else(|e| {
+                warn!(path = %path.display(), error = %e, "Ignoring unreadable profile");
+                Profile::default()
+            }),
+            Err(_) => Profile::default()
+        };
+        profile.path = Some(path);
+        profile
+    }
+
+    pub fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let text = toml::to_string(self).unwrap();
+        let saved = match path.parent() {
+            Some(dir) => fs::create_dir_all(dir).and_then(|_| fs::write(path, text)),
+            None => fs::write(path, text)
+        };
+        match saved {
+            Ok(()) => debug!(path = %path.display(), "Saved profile"),
+            Err(e) => warn!(path = %path.display(), error = %e, "Failed to save profile")
+        }
+    }
+
+    // Drawings waiting for a server are kept next to the profile, one file per name
+    // and server: the server only takes one name per connection, so drawings queued
+    // under another would have it turn away everything sent after them
+    pub fn outbox_path(&self, name: &str, server: &str) -> Option<PathBuf> {
+        let dir = self.path.as_ref()?.parent()?;
+        let safe = |text: &str| -> String { text.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '_' }).collect() };
+        Some(dir.join(format!("outbox-{}-{}.bin", safe(name), safe(server))))
+    }
+
+    pub fn remember_server(&mut self, server: &str) {
+        self.servers.retain(|known| known != server);
+        self.servers.insert(0, server.to_string());
+        self.servers.truncate(MAX_RECENT_SERVERS);
+    }
+}
+
+fn profile_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("PSRS_PROFILE") {
+        return Some(PathBuf::from(path));
+    }
+    let dir = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    }?;
+    Some(dir.join("pictosendrs").join("profile.toml"))
+}